@@ -39,10 +39,14 @@ pub struct Args {
     #[arg(long, short, default_value_t=1)]
     pub retries: u32,
 
-    /// How long to wait for a reply (in ms)
+    /// How long to wait for a reply (in ms), until the round trip time of a target is known
     #[arg(long, short, default_value_t=1000)]
     pub poll: u32,
 
+    /// The lowest timeout (in ms) once it is adapted to the round trip time of a target
+    #[arg(long, default_value_t=500)]
+    pub min_timeout: u32,
+
     /// Output format (disabled)
     #[arg(value_enum, long, short='f', default_value_t=OutputFormat::Plain)]
     pub output_format: OutputFormat,
@@ -63,6 +67,38 @@ pub struct Args {
     #[arg(long)]
     pub spread: Option<u64>,
 
+    /// Find the responding targets with a stateless raw socket sweep first, only those are scanned (IPv4 only, needs CAP_NET_RAW)
+    #[arg(long, action=clap::ArgAction::SetTrue)]
+    pub stateless: bool,
+
+    /// Packets per second sent by the stateless sweep
+    #[arg(long)]
+    pub stateless_rate: Option<u32>,
+
+    /// Prefix length of the IPv4 networks targets are interleaved and limited by
+    #[arg(long, default_value_t=24)]
+    pub prefix_v4: u8,
+
+    /// Prefix length of the IPv6 networks targets are interleaved and limited by
+    #[arg(long, default_value_t=48)]
+    pub prefix_v6: u8,
+
+    /// Targets of one network scanned at the same time
+    #[arg(long)]
+    pub targets_per_prefix: Option<usize>,
+
+    /// Packets per second sent to one network
+    #[arg(long)]
+    pub prefix_rate: Option<u32>,
+
+    /// Report the progress, as a status line on a terminal
+    #[arg(long, action=clap::ArgAction::SetTrue)]
+    pub progress: bool,
+
+    /// Send from this port on every thread (SO_REUSEPORT)
+    #[arg(long)]
+    pub source_port: Option<u16>,
+
     // /// Attempt daemon identification
     #[arg(long="no-identify", action = clap::ArgAction::SetFalse)]
     pub identify: bool,
@@ -70,6 +106,70 @@ pub struct Args {
     /// Do not attempt daemon identification
     #[arg(long="identify", overrides_with = "identify")]
     pub _no_identify: bool,
+
+    /// Probes to run on every target, in order
+    #[arg(long, value_delimiter=',', default_value=ntpscan::probe::DEFAULT_PROBES)]
+    pub probes: Vec<String>,
+
+    /// Run the probes of a target at the same time instead of one after another
+    #[arg(long, action=clap::ArgAction::SetTrue)]
+    pub parallel_probes: bool,
+
+    /// NTP versions the identify probe sends a mode 3 request for
+    #[arg(long, value_delimiter=',', default_value="0,1,2,3,4,5,6,7", value_parser=clap::value_parser!(u8).range(0..=7))]
+    pub identify_versions: Vec<u8>,
+
+    /// Mode 3 packet the identify probe is based on
+    #[arg(value_enum, long, default_value_t=ClientTemplate::Zero)]
+    pub client_template: ClientTemplate,
+
+    /// Poll field of the mode 3 packets (overrides the template)
+    #[arg(long, allow_negative_numbers=true)]
+    pub client_poll: Option<i8>,
+
+    /// Precision field of the mode 3 packets (overrides the template)
+    #[arg(long, allow_negative_numbers=true)]
+    pub client_precision: Option<i8>,
+
+    /// Stratum field of the mode 3 packets (overrides the template)
+    #[arg(long)]
+    pub client_stratum: Option<u8>,
+
+    /// Versions to send mode 6 requests with
+    #[arg(long, value_delimiter=',', default_value="3", value_parser=clap::value_parser!(u8).range(0..=7))]
+    pub mode6_versions: Vec<u8>,
+
+    /// Versions to send mode 7 requests with
+    #[arg(long, value_delimiter=',', default_value="2", value_parser=clap::value_parser!(u8).range(0..=7))]
+    pub mode7_versions: Vec<u8>,
+
+    /// Key file in the ntp.keys format, mode 6 and mode 7 requests are signed with --control-key
+    #[arg(long, value_hint=FilePath)]
+    pub keys: Option<String>,
+
+    /// Keyid to sign mode 6 and mode 7 requests with, they are not signed without it
+    #[arg(long, requires="keys")]
+    pub control_key: Option<u32>,
+
+    /// Keyid to sign the mode 3 requests with, to check if the servers authenticate their responses
+    #[arg(long, requires="keys")]
+    pub client_key: Option<u32>,
+
+    /// Port of the NTS key establishment server
+    #[arg(long, default_value_t=ntpscan::nts::NTS_KE_PORT)]
+    pub nts_ke_port: u16,
+
+    /// NTS exchanges run at the same time, each blocks a thread
+    #[arg(long, default_value_t=16)]
+    pub nts_workers: usize,
+
+    /// Build the ntp hierarchy from the refids and write it to this file
+    #[arg(long, value_hint=FilePath)]
+    pub hierarchy: Option<String>,
+
+    /// Format of the hierarchy graph
+    #[arg(value_enum, long, default_value_t=GraphFormat::Dot)]
+    pub hierarchy_format: GraphFormat,
 }
 
 #[derive(ValueEnum, Clone, Debug)]
@@ -77,4 +177,18 @@ pub enum OutputFormat {
     Plain,
     CSV,
     XML
-}
\ No newline at end of file
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+pub enum ClientTemplate {
+    /// all fields zero
+    Zero,
+    /// the probe used by zmap and nmap
+    Nmap,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+pub enum GraphFormat {
+    Dot,
+    Graphml,
+}
@@ -0,0 +1,418 @@
+//! Chrony's command and monitoring protocol (cmdmon), what `chronyc` uses.
+//! Sends the unauthenticated `tracking`, `sources`, `sourcestats` and `serverstats`
+//! requests to port 323, which chronyd only answers when cmdmon is exposed to the network.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+
+use crate::packets::cmdmon;
+use crate::packets::AnyNTPPacket;
+use crate::packets::CmdmonPacket;
+use crate::probe::Probe;
+use crate::probe::ProbeConfig;
+use crate::scan::ScanResult;
+use crate::scan::ScanState;
+use crate::scan::ScanTypeStatus;
+
+/// the amount of sources to request the data and statistics of
+static MAX_SOURCES: u32 = 32;
+
+/// Size of an IPAddr: the address, the family and padding
+static IP_ADDR_LEN: usize = 20;
+
+/// Chrony's 32 bit floating point format, a 7 bit exponent and a 25 bit coefficient
+pub fn decode_float(bytes: [u8; 4]) -> f64 {
+    let x = u32::from_be_bytes(bytes);
+    let mut exp = (x >> 25) as i32;
+    if exp >= 1 << 6 {
+        exp -= 1 << 7;
+    }
+    let mut coef = (x % (1 << 25)) as i32;
+    if coef >= 1 << 24 {
+        coef -= 1 << 25;
+    }
+    coef as f64 * 2f64.powi(exp - 25)
+}
+
+fn decode_ip(bytes: &[u8]) -> Option<IpAddr> {
+    match u16::from_be_bytes([bytes[16], bytes[17]]) {
+        1 => Some(IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))),
+        2 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[0..16]).unwrap()))),
+        _ => None,
+    }
+}
+
+fn u16_at(data: &[u8], i: usize) -> u16 {
+    u16::from_be_bytes([data[i], data[i + 1]])
+}
+
+fn u32_at(data: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes(data[i..i + 4].try_into().unwrap())
+}
+
+fn float_at(data: &[u8], i: usize) -> f64 {
+    decode_float(data[i..i + 4].try_into().unwrap())
+}
+
+/// The reference the server is synchronised to, `chronyc tracking`
+#[derive(Debug, Clone)]
+pub struct Tracking {
+    pub ref_id: u32,
+    pub ip: Option<IpAddr>,
+    pub stratum: u16,
+    pub leap_status: u16,
+    pub freq_ppm: f64,
+    pub root_delay: f64,
+    pub root_dispersion: f64,
+}
+
+impl Tracking {
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < 76 {
+            return None;
+        }
+        Some(Self {
+            ref_id: u32_at(data, 0),
+            ip: decode_ip(&data[4..24]),
+            stratum: u16_at(data, 24),
+            leap_status: u16_at(data, 26),
+            freq_ppm: float_at(data, 52),
+            root_delay: float_at(data, 64),
+            root_dispersion: float_at(data, 68),
+        })
+    }
+
+    pub fn summary(&self) -> String {
+        let reference = match self.ip {
+            Some(ip) => ip.to_string(),
+            None => String::from_utf8_lossy(&self.ref_id.to_be_bytes()).trim_end_matches('\0').to_string(),
+        };
+        format!("{} stratum {} leap {} freq {:.3}ppm root delay {:.6}s dispersion {:.6}s",
+            reference, self.stratum, self.leap_status, self.freq_ppm, self.root_delay, self.root_dispersion)
+    }
+}
+
+static SOURCE_STATES: [&str; 6] = ["selected", "nonselectable", "falseticker", "jittery", "unselected", "selectable"];
+static SOURCE_MODES: [&str; 3] = ["server", "peer", "refclock"];
+
+/// A source of the server, `chronyc sources`
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub index: u32,
+    /// None for reference clocks
+    pub ip: Option<IpAddr>,
+    /// the refid of a reference clock
+    pub ref_id: u32,
+    pub poll: i16,
+    pub stratum: u16,
+    pub state: u16,
+    pub mode: u16,
+    pub reachability: u16,
+    /// the offset of the last sample
+    pub offset: f64,
+}
+
+impl Source {
+    fn decode(index: u32, data: &[u8]) -> Option<Self> {
+        if data.len() < 48 {
+            return None;
+        }
+        Some(Self {
+            index,
+            ip: decode_ip(&data[0..IP_ADDR_LEN]),
+            ref_id: u32_at(data, 0),
+            poll: u16_at(data, 20) as i16,
+            stratum: u16_at(data, 22),
+            state: u16_at(data, 24),
+            mode: u16_at(data, 26),
+            reachability: u16_at(data, 30),
+            offset: float_at(data, 40),
+        })
+    }
+
+    pub fn name(&self) -> String {
+        match self.ip {
+            Some(ip) => ip.to_string(),
+            None => String::from_utf8_lossy(&self.ref_id.to_be_bytes()).trim_end_matches('\0').to_string(),
+        }
+    }
+
+    /// `address/stratum/reach/poll/offset/mode/state`
+    pub fn summary(&self) -> String {
+        format!("{}/{}/{:o}/{}/{:.6}/{}/{}", self.name(), self.stratum, self.reachability, self.poll, self.offset,
+            SOURCE_MODES.get(self.mode as usize).unwrap_or(&"?"),
+            SOURCE_STATES.get(self.state as usize).unwrap_or(&"?"))
+    }
+}
+
+/// The statistics of a source, `chronyc sourcestats`
+#[derive(Debug, Clone)]
+pub struct SourceStats {
+    pub index: u32,
+    pub ip: Option<IpAddr>,
+    pub n_samples: u32,
+    pub span_seconds: u32,
+    pub std_dev: f64,
+    pub est_offset: f64,
+}
+
+impl SourceStats {
+    fn decode(index: u32, data: &[u8]) -> Option<Self> {
+        if data.len() < 56 {
+            return None;
+        }
+        Some(Self {
+            index,
+            ip: decode_ip(&data[4..24]),
+            n_samples: u32_at(data, 24),
+            span_seconds: u32_at(data, 32),
+            std_dev: float_at(data, 36),
+            est_offset: float_at(data, 48),
+        })
+    }
+}
+
+/// Names of the server statistics, chrony 4.4 and up send them as 64 bit counters
+static SERVER_STATS: [&str; 11] = ["ntp_hits", "nke_hits", "cmd_hits", "ntp_drops", "nke_drops", "cmd_drops",
+    "log_drops", "ntp_auth_hits", "ntp_interleaved_hits", "ntp_timestamps", "ntp_span_seconds"];
+static SERVER_STATS_V1: [&str; 5] = ["ntp_hits", "cmd_hits", "ntp_drops", "cmd_drops", "log_drops"];
+
+/// The counters of `chronyc serverstats`, their number depends on the chrony version
+fn decode_server_stats(reply: u16, data: &[u8]) -> Vec<(&'static str, u64)> {
+    let (names, wide): (&[&str], bool) = match reply {
+        r if r == cmdmon::RPY_SERVER_STATS => (&SERVER_STATS_V1, false),
+        r if r == cmdmon::RPY_SERVER_STATS2 => (&SERVER_STATS[..8], false),
+        r if r == cmdmon::RPY_SERVER_STATS3 => (&SERVER_STATS, false),
+        r if r == cmdmon::RPY_SERVER_STATS4 => (&SERVER_STATS, true),
+        _ => return vec![],
+    };
+    let size = if wide { 8 } else { 4 };
+    names.iter().zip(data.chunks_exact(size)).map(|(name, c)| {
+        let value = if wide { u64::from_be_bytes(c.try_into().unwrap()) } else { u32_at(c, 0) as u64 };
+        (*name, value)
+    }).collect()
+}
+
+pub fn command_name(command: u16) -> &'static str {
+    match command {
+        c if c == cmdmon::REQ_N_SOURCES => "n_sources",
+        c if c == cmdmon::REQ_SOURCE_DATA => "sources",
+        c if c == cmdmon::REQ_TRACKING => "tracking",
+        c if c == cmdmon::REQ_SOURCESTATS => "sourcestats",
+        c if c == cmdmon::REQ_SERVER_STATS => "serverstats",
+        _ => "unknown",
+    }
+}
+
+pub fn status_name(status: u16) -> String {
+    let name = match status {
+        s if s == cmdmon::STT_SUCCESS => "ok",
+        s if s == cmdmon::STT_UNAUTH => "unauth",
+        s if s == cmdmon::STT_NOHOSTACCESS => "nohostaccess",
+        s if s == cmdmon::STT_BADPKTVERSION => "badpktversion",
+        1 => "failed",
+        3 => "invalid",
+        4 => "nosuchsource",
+        6 => "notenabled",
+        19 => "badpktlength",
+        _ => return format!("status{}", status),
+    };
+    name.to_string()
+}
+
+/// What the server answered over cmdmon
+#[derive(Debug, Clone, Default)]
+pub struct CmdmonStatus {
+    /// protocol version of the replies
+    pub version: Option<u8>,
+    /// the status of the first reply to every command
+    pub statuses: Vec<(u16, u16)>,
+    pub tracking: Option<Tracking>,
+    pub n_sources: Option<u32>,
+    pub sources: Vec<Source>,
+    pub sourcestats: Vec<SourceStats>,
+    pub server_stats: Vec<(&'static str, u64)>,
+}
+
+impl CmdmonStatus {
+    /// The server speaks cmdmon, even if it only answered with errors
+    pub fn responded(&self) -> bool {
+        self.version.is_some()
+    }
+
+    /// `version command:status ...`, used in the csv output
+    pub fn summary(&self) -> String {
+        let mut parts = vec![format!("v{}", self.version.unwrap_or(0))];
+        parts.extend(self.statuses.iter().map(|(c, s)| format!("{}:{}", command_name(*c), status_name(*s))));
+        if let Some(n) = self.n_sources {
+            parts.push(format!("n_sources={}", n));
+        }
+        parts.extend(self.server_stats.iter().map(|(name, value)| format!("{}={}", name, value)));
+        parts.join(" ")
+    }
+
+    /// the statistics of a source, as `samples/span/offset/sd`
+    pub fn stats_of(&self, source: &Source) -> Option<String> {
+        self.sourcestats.iter().find(|s| s.index == source.index && s.ip == source.ip)
+            .map(|s| format!("{}/{}/{:.6}/{:.6}", s.n_samples, s.span_seconds, s.est_offset, s.std_dev))
+    }
+
+    fn record_status(&mut self, command: u16, status: u16) {
+        if !self.statuses.iter().any(|(c, _)| *c == command) {
+            self.statuses.push((command, status));
+        }
+    }
+}
+
+pub struct CmdmonProbe {
+    retries: u32,
+    /// requests without a reply yet, by sequence number
+    outstanding: HashMap<u32, CmdmonPacket>,
+    status: CmdmonStatus,
+}
+
+impl CmdmonProbe {
+    pub fn new(_config: &ProbeConfig) -> Self {
+        Self {
+            retries: 0,
+            outstanding: HashMap::new(),
+            status: CmdmonStatus::default(),
+        }
+    }
+
+    /// `reply_len` is the size of the data of the expected reply, the request is padded to match it
+    fn queue_request(&mut self, state: &mut ScanState, command: u16, data: Vec<u8>, reply_len: usize) {
+        let mut pkt = CmdmonPacket::request(command, data, cmdmon::REPLY_HEADER_LEN + reply_len);
+        pkt.sequence = rand::random();
+        self.outstanding.insert(pkt.sequence, pkt.clone());
+        state.queue.push_back(AnyNTPPacket::Cmdmon(pkt));
+    }
+
+    fn handle_reply(&mut self, state: &mut ScanState, request: &CmdmonPacket, pkt: &CmdmonPacket) {
+        let index = request.data.get(0..4).map_or(0, |i| u32::from_be_bytes(i.try_into().unwrap()));
+        match pkt.reply {
+            r if r == cmdmon::RPY_TRACKING => {
+                self.status.tracking = Tracking::decode(&pkt.data);
+                if let Some(tracking) = &self.status.tracking {
+                    vprintln!("{} (cmdmon) tracking {}", state.address, tracking.summary());
+                }
+            },
+            r if r == cmdmon::RPY_N_SOURCES && pkt.data.len() >= 4 => {
+                let n = u32_at(&pkt.data, 0);
+                vprintln!("{} (cmdmon) has {} sources", state.address, n);
+                self.status.n_sources = Some(n);
+                for i in 0..n.min(MAX_SOURCES) {
+                    self.queue_request(state, cmdmon::REQ_SOURCE_DATA, i.to_be_bytes().to_vec(), 48);
+                    self.queue_request(state, cmdmon::REQ_SOURCESTATS, i.to_be_bytes().to_vec(), 56);
+                }
+            },
+            r if r == cmdmon::RPY_SOURCE_DATA => {
+                if let Some(source) = Source::decode(index, &pkt.data) {
+                    vprintln!("{} (cmdmon) source {}", state.address, source.summary());
+                    self.status.sources.push(source);
+                }
+            },
+            r if r == cmdmon::RPY_SOURCESTATS => {
+                self.status.sourcestats.extend(SourceStats::decode(index, &pkt.data));
+            },
+            r => {
+                self.status.server_stats = decode_server_stats(r, &pkt.data);
+                if self.status.server_stats.is_empty() {
+                    vprintln!("{} (cmdmon) unknown reply {} to {}", state.address, r, command_name(pkt.command));
+                }
+            },
+        }
+    }
+}
+
+impl Probe for CmdmonProbe {
+    fn name(&self) -> &'static str {
+        "cmdmon"
+    }
+
+    fn init(&mut self, state: &mut ScanState) {
+        self.queue_request(state, cmdmon::REQ_TRACKING, vec![], 76);
+        self.queue_request(state, cmdmon::REQ_N_SOURCES, vec![], 4);
+        // the biggest serverstats reply has 21 counters of 64 bit
+        self.queue_request(state, cmdmon::REQ_SERVER_STATS, vec![], 168);
+    }
+
+    fn accepts(&self, pkt: &AnyNTPPacket) -> bool {
+        matches!(pkt, AnyNTPPacket::Cmdmon(_))
+    }
+
+    fn receive(&mut self, state: &mut ScanState, pkt: &AnyNTPPacket) -> ScanTypeStatus {
+        let AnyNTPPacket::Cmdmon(pkt) = pkt else {
+            vvprintln!("{} (cmdmon) received a non-cmdmon packet", state.address);
+            return ScanTypeStatus::Continue;
+        };
+        if pkt.pkt_type != cmdmon::PKT_TYPE_CMD_REPLY {
+            vprintln!("{} (cmdmon) received request instead of reply, quitting", state.address);
+            return ScanTypeStatus::Done;
+        }
+        let Some(request) = self.outstanding.remove(&pkt.sequence) else {
+            vvprintln!("{} (cmdmon) received a reply to a request we did not send", state.address);
+            return ScanTypeStatus::Continue;
+        };
+        self.status.version = Some(pkt.version);
+        self.status.record_status(pkt.command, pkt.status);
+        if pkt.status == cmdmon::STT_SUCCESS {
+            self.handle_reply(state, &request, pkt);
+        } else {
+            vprintln!("{} (cmdmon) {} failed with {} (protocol version {})",
+                state.address, command_name(pkt.command), status_name(pkt.status), pkt.version);
+        }
+
+        if self.outstanding.is_empty() {
+            ScanTypeStatus::Done
+        } else {
+            ScanTypeStatus::Continue
+        }
+    }
+
+    fn timeout(&mut self, state: &mut ScanState) -> ScanTypeStatus {
+        if self.outstanding.is_empty() || self.retries >= state.maxretries {
+            vprintln!("{} (cmdmon) timed out", state.address);
+            return ScanTypeStatus::Done;
+        }
+        self.retries += 1;
+        for pkt in self.outstanding.values_mut() {
+            pkt.attempt += 1;
+            state.queue.push_back(AnyNTPPacket::Cmdmon(pkt.clone()));
+        }
+        ScanTypeStatus::Continue
+    }
+
+    fn contribute(&self, result: &mut ScanResult) {
+        if self.status.responded() {
+            result.daemon_guess = "chrony";
+        }
+        result.cmdmon = Some(self.status.clone());
+    }
+}
+
+#[test]
+fn decode_cmdmon_replies() {
+    assert_eq!(decode_float(((2 << 25) | (1 << 23) as u32).to_be_bytes()), 1.0);
+    assert_eq!(decode_float(((0x7f << 25) | ((1 << 25) - 4) as u32).to_be_bytes()), -4.0 * 2f64.powi(-26));
+
+    let mut data = vec![0; 48];
+    data[0..4].copy_from_slice(&[192, 0, 2, 1]);
+    data[17] = 1;
+    data[20..22].copy_from_slice(&6u16.to_be_bytes());
+    data[23] = 2;
+    data[31] = 0o377;
+    let mut reply = CmdmonPacket::request(cmdmon::REQ_SOURCE_DATA, data, 0);
+    reply.pkt_type = cmdmon::PKT_TYPE_CMD_REPLY;
+    reply.reply = cmdmon::RPY_SOURCE_DATA;
+    reply.sequence = 7;
+    let parsed = CmdmonPacket::parse(&reply.pack()).unwrap();
+    assert_eq!(parsed.sequence, 7);
+    assert_eq!(parsed.reply, cmdmon::RPY_SOURCE_DATA);
+    let source = Source::decode(0, &parsed.data).unwrap();
+    assert_eq!(source.summary(), "192.0.2.1/2/377/6/0.000000/server/selected");
+
+    let request = CmdmonPacket::request(cmdmon::REQ_TRACKING, vec![], cmdmon::REPLY_HEADER_LEN + 76);
+    assert_eq!(request.pack().len(), 104);
+}
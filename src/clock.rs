@@ -0,0 +1,142 @@
+//! Reads the clock variables (mode 6 READCLOCK) of the reference clock of a stratum 1 server,
+//! like `ntpq -c cv` does. Association 0 makes ntpd pick the system peer
+//! or else the first reference clock it has.
+use crate::packets::control;
+use crate::keys::Key;
+use crate::packets::AnyNTPPacket;
+use crate::packets::ControlReassembly;
+use crate::packets::NtpControlMessage;
+use crate::probe::Probe;
+use crate::probe::ProbeConfig;
+use crate::scan::ScanResult;
+use crate::scan::ScanState;
+use crate::scan::ScanTypeStatus;
+use crate::variables::parse_variables;
+
+/// The clock variables of a reference clock
+#[derive(Debug, Clone)]
+pub struct ClockVariables {
+    pub assoc_id: u16,
+    pub variables: Vec<(String, String)>,
+}
+
+impl ClockVariables {
+    pub fn variable(&self, name: &str) -> Option<&str> {
+        self.variables.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// the driver name, older ntpd versions only report the driver type number
+    pub fn driver(&self) -> Option<&str> {
+        self.variable("name").or(self.variable("type"))
+    }
+
+    /// `name=value` pairs of the interesting variables, the timecode is left out
+    pub fn summary(&self) -> String {
+        ["name", "device", "poll", "noreply", "badformat", "baddata", "fudgetime1", "fudgetime2", "stratum", "refid", "flags"]
+            .iter()
+            .filter_map(|n| self.variable(n).map(|v| format!("{}={}", n, v)))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+pub struct ClockProbe {
+    retries: u32,
+    sequence: u16,
+    versions: Vec<u8>,
+    key: Option<Key>,
+    reassembly: ControlReassembly,
+    clock: Option<ClockVariables>,
+}
+
+impl ClockProbe {
+    pub fn new(config: &ProbeConfig) -> Self {
+        Self {
+            retries: 0,
+            sequence: rand::random(),
+            versions: config.mode6_versions.clone(),
+            key: config.control_key.clone(),
+            reassembly: ControlReassembly::new(),
+            clock: None,
+        }
+    }
+
+    fn queue_request(&self, state: &mut ScanState) {
+        for &version in &self.versions {
+            let mut msg = NtpControlMessage::empty();
+            msg.version = version;
+            msg.opcode = control::CTL_OP_READCLOCK;
+            msg.sequence = self.sequence;
+            if let Some(key) = &self.key {
+                key.sign_control(&mut msg);
+            }
+            state.queue.push_back(AnyNTPPacket::Control(msg));
+        }
+    }
+}
+
+impl Probe for ClockProbe {
+    fn name(&self) -> &'static str {
+        "clockvar"
+    }
+
+    /// only stratum 1 servers have a reference clock,
+    /// the probe runs if the stratum is not known yet
+    fn applicable(&self, state: &ScanState) -> bool {
+        state.stratum().is_none_or(|stratum| stratum == 1)
+    }
+
+    fn init(&mut self, state: &mut ScanState) {
+        self.queue_request(state);
+    }
+
+    fn accepts(&self, pkt: &AnyNTPPacket) -> bool {
+        pkt.as_control().is_some_and(|p| p.opcode == control::CTL_OP_READCLOCK && p.sequence == self.sequence)
+    }
+
+    fn receive(&mut self, state: &mut ScanState, pkt: &AnyNTPPacket) -> ScanTypeStatus {
+        let Some(pkt) = pkt.as_control() else {
+            vvprintln!("{} (mode 6) clock variables command received non-control packet", state.address);
+            return ScanTypeStatus::Continue;
+        };
+        if pkt.opcode != control::CTL_OP_READCLOCK {
+            vvprintln!("{} (mode 6) clock variables command received response with opcode {}", state.address, pkt.opcode);
+            return ScanTypeStatus::Continue;
+        }
+        if !pkt.response {
+            vprintln!("{} (mode 6) received request instead of response, quitting", state.address);
+            return ScanTypeStatus::Done;
+        }
+        if pkt.error {
+            // usually there is no reference clock
+            vprintln!("{} (mode 6) received error response to the clock variables request", state.address);
+            return ScanTypeStatus::Done;
+        }
+        if let Some(data) = self.reassembly.add(pkt) {
+            let clock = ClockVariables {
+                assoc_id: pkt.assoc_id,
+                variables: parse_variables(&String::from_utf8_lossy(&data)),
+            };
+            vprintln!("{} (mode 6) reference clock {}: {}", state.address, clock.assoc_id, clock.summary());
+            self.clock = Some(clock);
+            return ScanTypeStatus::Done;
+        }
+        ScanTypeStatus::Continue
+    }
+
+    fn timeout(&mut self, state: &mut ScanState) -> ScanTypeStatus {
+        if self.retries < state.maxretries {
+            self.retries += 1;
+            self.reassembly = ControlReassembly::new();
+            self.queue_request(state);
+            ScanTypeStatus::Continue
+        } else {
+            vprintln!("{} (mode 6) clock variables timed out", state.address);
+            ScanTypeStatus::Done
+        }
+    }
+
+    fn contribute(&self, result: &mut ScanResult) {
+        result.clock = self.clock.clone();
+    }
+}
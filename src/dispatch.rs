@@ -0,0 +1,129 @@
+//! When the scan threads share a source port the kernel hands a reply to any of their sockets.
+//! The [Dispatcher] knows which thread scans which target and forwards the reply to it.
+//! Replies from another address than the target are routed by the request they echo.
+use std::collections::HashMap;
+use std::mem;
+use std::net::IpAddr;
+use std::os::fd::AsFd;
+use std::os::fd::BorrowedFd;
+use std::sync::Arc;
+use std::sync::Mutex;
+use nix::sys::eventfd::EfdFlags;
+use nix::sys::eventfd::EventFd;
+use crate::packets::Correlation;
+use crate::scan::Correlations;
+use crate::socket::SockAddrInet;
+
+/// Packets forwarded to a thread, the eventfd wakes up its event loop
+struct Inbox {
+    packets: Mutex<Vec<(SockAddrInet, Vec<u8>)>>,
+    wake: EventFd,
+}
+
+pub struct Dispatcher {
+    /// which thread scans an ip
+    owners: Mutex<HashMap<IpAddr, usize>>,
+    /// the requests of the targets of every thread
+    correlations: Mutex<Correlations>,
+    inboxes: Vec<Inbox>,
+}
+
+impl Dispatcher {
+    pub fn new(threads: usize) -> nix::Result<Arc<Self>> {
+        let inboxes = (0..threads).map(|_| Ok(Inbox {
+            packets: Mutex::new(vec![]),
+            wake: EventFd::from_flags(EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC)?,
+        })).collect::<nix::Result<Vec<Inbox>>>()?;
+        Ok(Arc::new(Self { owners: Mutex::new(HashMap::new()), correlations: Mutex::new(Correlations::default()), inboxes }))
+    }
+
+    /// The side of the dispatcher a single thread uses
+    pub fn mailbox(self: &Arc<Self>, thread: usize) -> Mailbox {
+        assert!(thread < self.inboxes.len(), "no inbox for thread {thread}");
+        Mailbox { dispatcher: self.clone(), thread }
+    }
+}
+
+pub struct Mailbox {
+    dispatcher: Arc<Dispatcher>,
+    thread: usize,
+}
+
+impl Mailbox {
+    /// Replies from `ip` should be forwarded to this thread
+    pub fn claim(&self, ip: IpAddr) {
+        self.dispatcher.owners.lock().unwrap().insert(ip, self.thread);
+    }
+
+    /// The target is done, forget it and the requests sent to it
+    pub fn release(&self, target: SockAddrInet, ids: impl IntoIterator<Item = Correlation>) {
+        self.dispatcher.correlations.lock().unwrap().forget(target, ids);
+        let mut owners = self.dispatcher.owners.lock().unwrap();
+        if owners.get(&target.ip()) == Some(&self.thread) {
+            owners.remove(&target.ip());
+        }
+    }
+
+    /// A request was sent to `target`
+    pub fn register(&self, id: Correlation, target: SockAddrInet) {
+        self.dispatcher.correlations.lock().unwrap().insert(id, target);
+    }
+
+    /// The target of any thread that sent the request, None if no or more than one target did
+    pub fn find(&self, id: &Correlation) -> Option<SockAddrInet> {
+        self.dispatcher.correlations.lock().unwrap().find(id)
+    }
+
+    /// Hand a packet from `src` to the thread that scans `target`, false if no other thread does
+    pub fn forward(&self, target: SockAddrInet, src: SockAddrInet, raw: &[u8]) -> bool {
+        let Some(&owner) = self.dispatcher.owners.lock().unwrap().get(&target.ip()) else {
+            return false;
+        };
+        if owner == self.thread {
+            return false;
+        }
+        vvprintln!("forwarding {} bytes from {src} to thread {owner}", raw.len());
+        let inbox = &self.dispatcher.inboxes[owner];
+        inbox.packets.lock().unwrap().push((src, raw.to_vec()));
+        inbox.wake.write(1).expect("failed to write to eventfd");
+        true
+    }
+
+    /// The packets other threads forwarded to this one
+    pub fn take(&self) -> Vec<(SockAddrInet, Vec<u8>)> {
+        let inbox = &self.dispatcher.inboxes[self.thread];
+        // EAGAIN when another wakeup already drained the counter
+        let _ = inbox.wake.read();
+        mem::take(&mut *inbox.packets.lock().unwrap())
+    }
+}
+
+impl AsFd for Mailbox {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.dispatcher.inboxes[self.thread].wake.as_fd()
+    }
+}
+
+#[test]
+fn forward_to_owner() {
+    let dispatcher = Dispatcher::new(2).unwrap();
+    let (a, b) = (dispatcher.mailbox(0), dispatcher.mailbox(1));
+    let target = SockAddrInet::new("192.0.2.1".parse().unwrap(), 123);
+    b.claim(target.ip());
+    assert!(a.forward(target, target, &[1, 2, 3]));
+    assert!(!b.forward(target, target, &[4]));
+    assert_eq!(b.take(), vec![(target, vec![1, 2, 3])]);
+    assert!(a.take().is_empty());
+
+    // a reply from another address is routed by its request
+    let id = Correlation::Timestamp(0x1234);
+    let other = SockAddrInet::new("198.51.100.7".parse().unwrap(), 123);
+    b.register(id, target);
+    assert_eq!(a.find(&id), Some(target));
+    assert!(a.forward(a.find(&id).unwrap(), other, &[5]));
+    assert_eq!(b.take(), vec![(other, vec![5])]);
+
+    b.release(target, [id]);
+    assert_eq!(a.find(&id), None);
+    assert!(!a.forward(target, target, &[1]));
+}
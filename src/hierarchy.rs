@@ -0,0 +1,313 @@
+//! Reconstructs the synchronisation hierarchy of the scanned servers from their refids.
+//!
+//! For stratum 2 and up the refid identifies the upstream server.
+//! For an IPv4 upstream it is simply the address,
+//! for an IPv6 upstream it is the first four octets of the MD5 hash of the address (RFC 5905).
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+
+use md5::Digest;
+use md5::Md5;
+
+use crate::scan::ScanResult;
+
+/// A server in the hierarchy, either one we scanned or an upstream we only know of through a refid
+pub struct Node {
+    pub ip: IpAddr,
+    pub stratum: Option<u8>,
+    pub refid: Option<[u8; 4]>,
+    /// false if this node was only seen as the upstream of another node
+    pub scanned: bool,
+    /// the node this node synchronises to
+    pub upstream: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct Hierarchy {
+    pub nodes: Vec<Node>,
+    index: HashMap<IpAddr, usize>,
+}
+
+/// How many upstreams are listed in the report
+static TOP_UPSTREAMS: usize = 10;
+
+impl Hierarchy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a scanned server, servers that did not respond to mode 3 are ignored
+    pub fn insert(&mut self, ip: IpAddr, stratum: Option<u8>, refid: Option<[u8; 4]>) {
+        if stratum.is_none() {
+            return;
+        }
+        match self.index.get(&ip) {
+            Some(&i) => {
+                let node = &mut self.nodes[i];
+                node.stratum = stratum;
+                node.refid = refid;
+                node.scanned = true;
+            },
+            None => {
+                self.index.insert(ip, self.nodes.len());
+                self.nodes.push(Node { ip, stratum, refid, scanned: true, upstream: None });
+            },
+        }
+    }
+
+    pub fn insert_result(&mut self, res: &ScanResult) {
+        self.insert(res.address.ip(), res.stratum, res.refid.as_ref().map(|r| r.bytes()));
+    }
+
+    /// Join the refids to the scanned addresses.
+    /// Refids that cannot be joined but look like an IPv4 address are added as unscanned nodes.
+    pub fn link(&mut self) {
+        let mut hashes: HashMap<[u8; 4], usize> = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let IpAddr::V6(ip) = node.ip {
+                hashes.entry(ipv6_refid(&ip.octets())).or_insert(i);
+            }
+        }
+
+        for i in 0..self.nodes.len() {
+            let Some(refid) = self.upstream_refid(i) else {
+                continue;
+            };
+            let ipv4 = IpAddr::V4(Ipv4Addr::from(refid));
+            let upstream = match self.index.get(&ipv4) {
+                Some(&j) => j,
+                None => match hashes.get(&refid) {
+                    Some(&j) => j,
+                    None => {
+                        if !is_plausible_upstream(&Ipv4Addr::from(refid)) {
+                            continue;
+                        }
+                        self.index.insert(ipv4, self.nodes.len());
+                        self.nodes.push(Node { ip: ipv4, stratum: None, refid: None, scanned: false, upstream: None });
+                        self.nodes.len() - 1
+                    },
+                },
+            };
+            self.nodes[i].upstream = Some(upstream);
+        }
+    }
+
+    /// The refid of a node if it refers to another server
+    fn upstream_refid(&self, i: usize) -> Option<[u8; 4]> {
+        let node = &self.nodes[i];
+        match node.stratum {
+            Some(2..=15) if node.scanned => node.refid,
+            _ => None,
+        }
+    }
+
+    /// Scanned servers whose upstream is not one of the scanned servers
+    pub fn orphans(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|&i| self.nodes[i].scanned && self.nodes[i].stratum != Some(1))
+            .filter(|&i| match self.nodes[i].upstream {
+                Some(j) => !self.nodes[j].scanned,
+                None => true,
+            })
+            .collect()
+    }
+
+    /// Every synchronisation loop, each as the list of nodes on it
+    pub fn loops(&self) -> Vec<Vec<usize>> {
+        let mut loops = vec![];
+        let mut visited = vec![false; self.nodes.len()];
+        for start in 0..self.nodes.len() {
+            let mut path = vec![];
+            let mut on_path = HashSet::new();
+            let mut cur = Some(start);
+            while let Some(i) = cur {
+                if on_path.contains(&i) {
+                    let pos = path.iter().position(|&p| p == i).unwrap();
+                    loops.push(path[pos..].to_vec());
+                    break;
+                }
+                if visited[i] {
+                    break;
+                }
+                visited[i] = true;
+                on_path.insert(i);
+                path.push(i);
+                cur = self.nodes[i].upstream;
+            }
+        }
+        loops
+    }
+
+    /// For every node the amount of direct and transitive dependents,
+    /// sorted on the transitive dependents
+    pub fn dependents(&self) -> Vec<(usize, usize, usize)> {
+        let mut direct = vec![0; self.nodes.len()];
+        let mut total = vec![0; self.nodes.len()];
+        for i in 0..self.nodes.len() {
+            if let Some(j) = self.nodes[i].upstream {
+                direct[j] += 1;
+            }
+            // walk up the chain, stopping when we come across a loop
+            let mut seen = HashSet::from([i]);
+            let mut cur = self.nodes[i].upstream;
+            while let Some(j) = cur {
+                if !seen.insert(j) {
+                    break;
+                }
+                total[j] += 1;
+                cur = self.nodes[j].upstream;
+            }
+        }
+        let mut deps: Vec<(usize, usize, usize)> = (0..self.nodes.len())
+            .filter(|&i| direct[i] > 0)
+            .map(|i| (i, direct[i], total[i]))
+            .collect();
+        deps.sort_by(|a, b| b.2.cmp(&a.2).then(b.1.cmp(&a.1)));
+        deps
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let scanned = self.nodes.iter().filter(|n| n.scanned).count();
+        let edges = self.nodes.iter().filter(|n| n.upstream.is_some()).count();
+        writeln!(out, "hierarchy: {} servers, {} upstreams outside the scan, {} links", scanned, self.nodes.len() - scanned, edges).unwrap();
+
+        let orphans = self.orphans();
+        writeln!(out, "orphans ({}):", orphans.len()).unwrap();
+        for i in orphans {
+            let node = &self.nodes[i];
+            writeln!(out, "  {} stratum {} refid {}", node.ip, node.stratum.unwrap_or(0), self.refid_label(i)).unwrap();
+        }
+
+        let loops = self.loops();
+        writeln!(out, "loops ({}):", loops.len()).unwrap();
+        for l in loops {
+            let ips: Vec<String> = l.iter().map(|&i| self.nodes[i].ip.to_string()).collect();
+            writeln!(out, "  {} -> {}", ips.join(" -> "), ips[0]).unwrap();
+        }
+
+        writeln!(out, "most depended-upon upstreams:").unwrap();
+        for (i, direct, total) in self.dependents().into_iter().take(TOP_UPSTREAMS) {
+            writeln!(out, "  {} {} direct, {} total{}", self.nodes[i].ip, direct, total,
+                if self.nodes[i].scanned { "" } else { " (not scanned)" }).unwrap();
+        }
+        out
+    }
+
+    /// A printable refid, the address of the upstream if known
+    fn refid_label(&self, i: usize) -> String {
+        let node = &self.nodes[i];
+        match (node.upstream, node.refid) {
+            (Some(j), _) => self.nodes[j].ip.to_string(),
+            (None, Some(refid)) if node.stratum == Some(1) || node.stratum == Some(0) || node.stratum == Some(16) => {
+                refid.iter()
+                    .take_while(|&&b| b != 0)
+                    .map(|&b| if b.is_ascii_graphic() { (b as char).to_string() } else { format!("\\x{:02x}", b) })
+                    .collect()
+            },
+            (None, Some(refid)) => Ipv4Addr::from(refid).to_string(),
+            (None, None) => "".to_string(),
+        }
+    }
+
+    pub fn dot(&self) -> String {
+        let mut out = String::new();
+        out.push_str("digraph ntp {\n  rankdir=BT;\n");
+        for (i, node) in self.nodes.iter().enumerate() {
+            let mut label = node.ip.to_string();
+            if let Some(stratum) = node.stratum {
+                write!(label, "\\nstratum {}", stratum).unwrap();
+            }
+            if node.stratum == Some(1) {
+                write!(label, "\\n{}", self.refid_label(i)).unwrap();
+            }
+            let style = if node.scanned { "" } else { ", style=dashed" };
+            writeln!(out, "  \"{}\" [label=\"{}\"{}];", node.ip, label.replace('"', "\\\""), style).unwrap();
+        }
+        for node in &self.nodes {
+            if let Some(j) = node.upstream {
+                writeln!(out, "  \"{}\" -> \"{}\";", node.ip, self.nodes[j].ip).unwrap();
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn graphml(&self) -> String {
+        let mut out = String::new();
+        out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        out.push_str("<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+        out.push_str("  <key id=\"stratum\" for=\"node\" attr.name=\"stratum\" attr.type=\"int\"/>\n");
+        out.push_str("  <key id=\"refid\" for=\"node\" attr.name=\"refid\" attr.type=\"string\"/>\n");
+        out.push_str("  <key id=\"scanned\" for=\"node\" attr.name=\"scanned\" attr.type=\"boolean\"/>\n");
+        out.push_str("  <graph id=\"ntp\" edgedefault=\"directed\">\n");
+        for (i, node) in self.nodes.iter().enumerate() {
+            writeln!(out, "    <node id=\"{}\">", node.ip).unwrap();
+            if let Some(stratum) = node.stratum {
+                writeln!(out, "      <data key=\"stratum\">{}</data>", stratum).unwrap();
+            }
+            if node.refid.is_some() {
+                writeln!(out, "      <data key=\"refid\">{}</data>", xml_escape(&self.refid_label(i))).unwrap();
+            }
+            writeln!(out, "      <data key=\"scanned\">{}</data>", node.scanned).unwrap();
+            out.push_str("    </node>\n");
+        }
+        for node in &self.nodes {
+            if let Some(j) = node.upstream {
+                writeln!(out, "    <edge source=\"{}\" target=\"{}\"/>", node.ip, self.nodes[j].ip).unwrap();
+            }
+        }
+        out.push_str("  </graph>\n</graphml>\n");
+        out
+    }
+}
+
+/// The refid a server uses for an IPv6 upstream
+pub fn ipv6_refid(octets: &[u8; 16]) -> [u8; 4] {
+    let hash = Md5::digest(octets);
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// Filter out refids that cannot be the address of an upstream
+fn is_plausible_upstream(ip: &Ipv4Addr) -> bool {
+    !(ip.is_unspecified() || ip.is_loopback() || ip.is_broadcast() || ip.is_multicast())
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[test]
+fn hierarchy_links_refids() {
+    let mut h = Hierarchy::new();
+    let v6: IpAddr = "2001:db8::1".parse().unwrap();
+    let IpAddr::V6(v6addr) = v6 else { unreachable!() };
+    h.insert("192.0.2.1".parse().unwrap(), Some(1), Some(*b"GPS\0"));
+    h.insert("192.0.2.2".parse().unwrap(), Some(2), Some([192, 0, 2, 1]));
+    h.insert(v6, Some(3), Some([192, 0, 2, 2]));
+    h.insert("192.0.2.3".parse().unwrap(), Some(4), Some(ipv6_refid(&v6addr.octets())));
+    h.insert("192.0.2.4".parse().unwrap(), Some(3), Some([198, 51, 100, 7]));
+    h.link();
+
+    assert_eq!(h.nodes.len(), 6);
+    assert_eq!(h.nodes[3].upstream, Some(2));
+    assert_eq!(h.orphans(), vec![4]);
+    assert!(h.loops().is_empty());
+    let deps = h.dependents();
+    assert_eq!(deps[0], (0, 1, 3));
+}
+
+#[test]
+fn hierarchy_finds_loops() {
+    let mut h = Hierarchy::new();
+    h.insert("192.0.2.1".parse().unwrap(), Some(3), Some([192, 0, 2, 2]));
+    h.insert("192.0.2.2".parse().unwrap(), Some(3), Some([192, 0, 2, 1]));
+    h.insert("192.0.2.3".parse().unwrap(), Some(4), Some([192, 0, 2, 1]));
+    h.link();
+
+    assert_eq!(h.loops(), vec![vec![0, 1]]);
+    assert!(h.orphans().is_empty());
+}
@@ -1,38 +1,43 @@
 use std::collections::HashMap;
-use std::iter::Scan;
-use std::os::fd::AsFd;
-use std::os::fd::AsRawFd;
-use std::time::Duration;
-use nix::errno::Errno;
-use nix::poll::PollFd;
-use nix::poll::PollFlags;
-use nix::poll::PollTimeout;
-use nix::poll::poll;
-use nix::sys::socket::recvfrom;
-use nix::sys::socket::SockaddrLike;
-use crate::packets;
+use crate::keys::Key;
 use crate::packets::AnyNTPPacket;
+use crate::probe::Probe;
+use crate::probe::ProbeConfig;
+use crate::scan::ScanResult;
 use crate::scan::ScanTypeStatus;
-use crate::socket;
 use crate::packets::NTPPacket;
-use crate::send;
 use crate::scan::ScanState;
-use crate::vprintln;
-use crate::vvprintln;
-
-pub fn init(target: &mut ScanState) {
-    // we only attempt these versions because they provide the most interesting results,
-    // sending too many packets may lead to ratelimiting
-    let versions_to_scan = [0,1,2,3,4,5,6,7];
-
-    // push all version packets onto the queue
-    for vi in versions_to_scan {
-        let mut msg = NTPPacket::empty();
-        msg.version = vi;
+
+/// Sends a mode 3 request for every version to see which ones are answered
+pub struct IdentifyProbe {
+    /// key is the version sent
+    versions: HashMap<u8, VersionState>,
+    daemon_guess: Option<&'static str>,
+    versions_to_scan: Vec<u8>,
+    template: NTPPacket,
+    key: Option<Key>,
+}
+
+impl IdentifyProbe {
+    pub fn new(config: &ProbeConfig) -> Self {
+        Self {
+            versions: HashMap::new(),
+            daemon_guess: None,
+            versions_to_scan: config.identify_versions.clone(),
+            template: config.client_template.clone(),
+            key: config.client_key.clone(),
+        }
+    }
+
+    fn request(&self, version: u8, xmt: u64) -> AnyNTPPacket {
+        let mut msg = self.template.clone();
+        msg.version = version;
         msg.mode = 3;
-        msg.xmt = rand::random::<u64>();
-        target.versions.insert(vi, VersionState { retries: 0, xmt: msg.xmt, response: None });
-        target.queue.push_back(AnyNTPPacket::Standard(msg));
+        msg.xmt = xmt;
+        if let Some(key) = &self.key {
+            key.sign_client(&mut msg);
+        }
+        AnyNTPPacket::Standard(msg)
     }
 }
 
@@ -44,113 +49,137 @@ pub struct VersionState {
     pub response: Option<NTPPacket>
 }
 
-pub fn receive(target: &mut ScanState, pkt: &AnyNTPPacket) -> ScanTypeStatus {
-    let pkt = match pkt {
-        AnyNTPPacket::Standard(ntppacket) => ntppacket,
-        AnyNTPPacket::Control(_) => {
-            vprintln!("{} received control packet during version scan??", target.address);
-            return ScanTypeStatus::Continue;
-        },
-        AnyNTPPacket::Private(p) => {
-            vprintln!("{} received mode 7 packet during version scan?? {:?}", target.address, p);
-            return ScanTypeStatus::Continue;
-        },
-        _ => unreachable!(),
-    };
-
-    // rate kods are already handled timeout wise
-    // but when receiving one we should requeue a bunch of stuff
-
-    let mypkt = target.versions.iter_mut().find(|(_v, vs)| {
-        vs.xmt == pkt.org
-    });
-
-    match mypkt {
-        Some((version_sent, vs)) => {
-            match vs.response {
-                Some(_) => vprintln!("{} received duplicate response to version {version_sent}?", target.address),
-                None => {
-                    vprintln!("{} version {version_sent} was responded to with {}", target.address, pkt.version);
-                    vs.response = Some(pkt.clone());
-                },
-            }
-        },
-        None => {
-            vprintln!("{} received og timestamp which we did not send?", target.address);
-        },
+impl Probe for IdentifyProbe {
+    fn name(&self) -> &'static str {
+        "identify"
     }
 
-    if pkt.is_kod() && pkt.refidstr() == Some("RATE") {
-        // all unresolved packets should be retransmitted
-        // without increasing the retry counter
-        for (vi, vs) in &target.versions {
-            if vs.response.is_none() && target.queue
-                .iter()
-                .filter_map(|p| p.as_standard())
-                .find(|p| p.xmt == vs.xmt).is_none() {
-                let mut msg = NTPPacket::empty();
-                msg.version = *vi;
-                msg.mode = 3;
-                msg.xmt = vs.xmt;
-                target.queue.push_back(AnyNTPPacket::Standard(msg));
-            }
-        } 
+    fn init(&mut self, target: &mut ScanState) {
+        // sending too many packets may lead to ratelimiting,
+        // so the versions can be limited with --identify-versions
+
+        // push all version packets onto the queue
+        for &vi in &self.versions_to_scan {
+            let xmt = rand::random::<u64>();
+            self.versions.insert(vi, VersionState { retries: 0, xmt, response: None });
+            target.queue.push_back(self.request(vi, xmt));
+        }
     }
-    
-    // the following behavior and commented code was properly due to a bug of my own
-    //
-    // I have found that on some ntpd versions any subsequent requests are dropped
-    // as a temporary? fix we just give all unresolved versions
-    // another free try when a response is received
-    // target.versions.iter_mut()
-    // .filter(|(_vi, vs)| vs.response.is_none())
-    // .for_each(|(_vi, vs)| {
-    //     vs.retries = vs.retries.saturating_sub(1);
-    // });
-
-    if target.versions.iter().all(|(_, vs)| vs.response.is_some()) {
-        r#final(target);
-        return ScanTypeStatus::Done
+
+    fn accepts(&self, pkt: &AnyNTPPacket) -> bool {
+        pkt.as_standard().is_some_and(|p| self.versions.values().any(|vs| vs.xmt == p.org))
     }
 
-    ScanTypeStatus::Continue
-}
+    fn receive(&mut self, target: &mut ScanState, pkt: &AnyNTPPacket) -> ScanTypeStatus {
+        let pkt = match pkt {
+            AnyNTPPacket::Standard(ntppacket) => ntppacket,
+            AnyNTPPacket::Control(_) => {
+                vprintln!("{} received control packet during version scan??", target.address);
+                return ScanTypeStatus::Continue;
+            },
+            AnyNTPPacket::Private(p) => {
+                vprintln!("{} received mode 7 packet during version scan?? {:?}", target.address, p);
+                return ScanTypeStatus::Continue;
+            },
+            _ => unreachable!(),
+        };
+
+        // rate kods are already handled timeout wise
+        // but when receiving one we should requeue a bunch of stuff
+
+        let mypkt = self.versions.iter_mut().find(|(_v, vs)| {
+            vs.xmt == pkt.org
+        });
+
+        match mypkt {
+            Some((version_sent, vs)) => {
+                match vs.response {
+                    Some(_) => vprintln!("{} received duplicate response to version {version_sent}?", target.address),
+                    None => {
+                        vprintln!("{} version {version_sent} was responded to with {}", target.address, pkt.version);
+                        vs.response = Some(pkt.clone());
+                    },
+                }
+            },
+            None => {
+                vprintln!("{} received og timestamp which we did not send?", target.address);
+            },
+        }
 
-pub fn timeout(target: &mut ScanState) -> ScanTypeStatus {
-    if !target.queue.is_empty() {
-        return ScanTypeStatus::Continue
-    }
+        if pkt.is_kod() && pkt.refidstr() == Some("RATE") {
+            // all unresolved packets should be retransmitted
+            // without increasing the retry counter
+            for (vi, vs) in &self.versions {
+                if vs.response.is_none() && target.queue
+                    .iter()
+                    .filter_map(|p| p.as_standard())
+                    .find(|p| p.xmt == vs.xmt).is_none() {
+                    target.queue.push_back(self.request(*vi, vs.xmt));
+                }
+            }
+        }
 
-    if target.versions.iter().all(|(_vi, vs)| {
-        vs.response.is_some() || vs.retries == target.maxretries
-    }) {
-        vprintln!("{}: identify scan is accepting timeout", target.address);
-        r#final(target);
-        return ScanTypeStatus::Done;
+        // the following behavior and commented code was properly due to a bug of my own
+        //
+        // I have found that on some ntpd versions any subsequent requests are dropped
+        // as a temporary? fix we just give all unresolved versions
+        // another free try when a response is received
+        // self.versions.iter_mut()
+        // .filter(|(_vi, vs)| vs.response.is_none())
+        // .for_each(|(_vi, vs)| {
+        //     vs.retries = vs.retries.saturating_sub(1);
+        // });
+
+        if self.versions.iter().all(|(_, vs)| vs.response.is_some()) {
+            return ScanTypeStatus::Done
+        }
+
+        ScanTypeStatus::Continue
     }
 
-    vvprintln!("{} retrying mode 3 version(s)", target.address);
-
-    for (vi, vs) in &mut target.versions {
-        if vs.response.is_none() {
-            if vs.retries < target.maxretries {
-                let mut msg = NTPPacket::empty();
-                msg.version = *vi;
-                msg.mode = 3;
-                msg.xmt = vs.xmt;
-                vs.retries += 1;
-                target.queue.push_back(AnyNTPPacket::Standard(msg));
+    fn timeout(&mut self, target: &mut ScanState) -> ScanTypeStatus {
+        if !target.queue.is_empty() {
+            return ScanTypeStatus::Continue
+        }
+
+        if self.versions.iter().all(|(_vi, vs)| {
+            vs.response.is_some() || vs.retries == target.maxretries
+        }) {
+            vprintln!("{}: identify scan is accepting timeout", target.address);
+            return ScanTypeStatus::Done;
+        }
+
+        vvprintln!("{} retrying mode 3 version(s)", target.address);
+
+        let mut retry = vec![];
+        for (vi, vs) in &mut self.versions {
+            if vs.response.is_none() {
+                if vs.retries < target.maxretries {
+                    vs.retries += 1;
+                    retry.push((*vi, vs.xmt));
+                }
             }
         }
-    }
+        for (vi, xmt) in retry {
+            target.queue.push_back(self.request(vi, xmt));
+        }
 
-    ScanTypeStatus::Continue
+        ScanTypeStatus::Continue
 
-}
+    }
 
-pub fn r#final(state: &mut ScanState) {
-    vprintln!("{} responded with versions: {}", state.address, craft_version_state_str(&state.versions));
-    state.daemon_guess = Some(daemon_guess(state.versions.clone()));
+    fn finalise(&mut self, state: &mut ScanState) {
+        vprintln!("{} responded with versions: {}", state.address, craft_version_state_str(&self.versions));
+        self.daemon_guess = Some(daemon_guess(self.versions.clone()));
+    }
+
+    fn contribute(&self, result: &mut ScanResult) {
+        // don't overwrite a positive identification by another probe
+        if result.daemon_guess.is_empty() {
+            result.daemon_guess = self.daemon_guess.unwrap_or("");
+        }
+        result.versions = self.versions.iter().map(|(vi, vs)| (*vi, vs.response.as_ref().map(|p| p.version))).collect();
+    }
 }
 
 fn craft_version_state_str(versions: &HashMap<u8, VersionState>) -> String {
@@ -0,0 +1,240 @@
+//! Symmetric keys in the ntp.keys format, used to authenticate requests to servers we hold the keys of.
+//!
+//! Every line is `keyid type key`, where the key is used as ascii when it is 20 characters or less
+//! and as hex otherwise. The MAC is the digest of the key followed by the packet,
+//! or the AES-CMAC of the packet (RFC 8573).
+use std::fmt;
+use std::fs;
+
+use aes::Aes128;
+use cmac::Cmac;
+use cmac::Mac as _;
+use md5::Digest;
+use md5::Md5;
+use sha1::Sha1;
+
+use crate::packets::Mac;
+use crate::packets::NTPPacket;
+use crate::packets::NtpControlMessage;
+use crate::packets::NtpdPrivatePacket;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    Md5,
+    Sha1,
+    AesCmac,
+}
+
+impl KeyType {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "M" | "MD5" => Some(Self::Md5),
+            "SHA" | "SHA1" => Some(Self::Sha1),
+            "AES128CMAC" | "AES-128-CMAC" | "CMAC" => Some(Self::AesCmac),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Key {
+    pub id: u32,
+    pub kind: KeyType,
+    secret: Vec<u8>,
+}
+
+// the secret is left out so it doesn't end up in the logs
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Key {{ id: {}, kind: {:?} }}", self.id, self.kind)
+    }
+}
+
+impl Key {
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self.kind {
+            KeyType::Md5 => Md5::new().chain_update(&self.secret).chain_update(data).finalize().to_vec(),
+            KeyType::Sha1 => Sha1::new().chain_update(&self.secret).chain_update(data).finalize().to_vec(),
+            KeyType::AesCmac => {
+                let mut mac = <Cmac<Aes128> as cmac::Mac>::new_from_slice(&self.secret).expect("cmac keys are 16 bytes");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            },
+        }
+    }
+
+    pub fn mac(&self, data: &[u8]) -> Mac {
+        Mac {
+            keyid: self.id,
+            digest: self.digest(data),
+        }
+    }
+
+    /// Check a MAC over `data`, the bytes of the packet in front of the keyid
+    pub fn verify(&self, data: &[u8], mac: &Mac) -> bool {
+        mac.keyid == self.id && self.digest(data) == mac.digest
+    }
+
+    /// Sign a mode 3 request, the MAC directly follows the header
+    pub fn sign_client(&self, pkt: &mut NTPPacket) {
+        pkt.mac = None;
+        pkt.mac = Some(self.mac(&pkt.pack()));
+    }
+
+    /// Sign a mode 6 request, the MAC starts on a 64 bit boundary
+    pub fn sign_control(&self, msg: &mut NtpControlMessage) {
+        msg.mac = None;
+        let mut data = msg.pack();
+        data.resize(data.len().next_multiple_of(8), 0);
+        msg.mac = Some(self.mac(&data));
+    }
+
+    /// Sign a mode 7 request, the MAC covers the request up to and including the timestamp
+    pub fn sign_private(&self, msg: &mut NtpdPrivatePacket) {
+        msg.auth = true;
+        msg.tstamp = crate::packets::ntp_timestamp_now();
+        msg.mac = None;
+        msg.mac = Some(self.mac(&msg.pack()));
+    }
+}
+
+/// Parse the contents of a key file
+pub fn parse_keys(str: &str) -> anyhow::Result<Vec<Key>> {
+    let mut keys = vec![];
+    for (n, line) in str.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        anyhow::ensure!(fields.len() >= 3, "line {}: expected keyid, type and key", n + 1);
+        let id: u32 = fields[0].parse().map_err(|_| anyhow::anyhow!("line {}: invalid keyid {}", n + 1, fields[0]))?;
+        let Some(kind) = KeyType::from_name(fields[1]) else {
+            eprintln!("ignoring key {} with unsupported type {}", id, fields[1]);
+            continue;
+        };
+        let mut secret = if fields[2].len() <= 20 {
+            fields[2].as_bytes().to_vec()
+        } else {
+            (0..fields[2].len()).step_by(2)
+                .map(|i| fields[2].get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| anyhow::anyhow!("line {}: key {} is neither ascii nor hex", n + 1, id))?
+        };
+        if kind == KeyType::AesCmac {
+            // like ntpd, shorter keys are padded with zeros
+            secret.resize(16, 0);
+        }
+        keys.push(Key { id, kind, secret });
+    }
+    Ok(keys)
+}
+
+pub fn read_keys(path: &str) -> anyhow::Result<Vec<Key>> {
+    parse_keys(&fs::read_to_string(path)?)
+}
+
+/// How the responses to authenticated requests were signed
+#[derive(Debug, Clone, Default)]
+pub struct AuthStats {
+    /// responses with a valid MAC
+    pub valid: usize,
+    /// responses with a MAC that did not verify, these are dropped
+    pub invalid: usize,
+    /// responses without a MAC
+    pub unsigned: usize,
+    /// responses with only a keyid of 0, sent when the server does not know or trust the key
+    pub crypto_nak: usize,
+}
+
+impl AuthStats {
+    /// `valid/invalid/unsigned/crypto_nak`, used in the csv output
+    pub fn csv_str(&self) -> String {
+        format!("{}/{}/{}/{}", self.valid, self.invalid, self.unsigned, self.crypto_nak)
+    }
+
+    /// Whether the server authenticates its responses
+    pub fn verdict(&self) -> &'static str {
+        if self.invalid > 0 {
+            "invalid"
+        } else if self.valid > 0 {
+            "authenticated"
+        } else if self.crypto_nak > 0 {
+            "crypto-nak"
+        } else if self.unsigned > 0 {
+            "unsigned"
+        } else {
+            "no response"
+        }
+    }
+
+    /// Check the MAC of a response signed with `key`, `raw` is the whole packet.
+    /// Returns false if the MAC is invalid and the packet has to be dropped
+    pub fn check(&mut self, key: &Key, raw: &[u8], mac: Option<&Mac>) -> bool {
+        match mac {
+            Some(mac) if mac.keyid == 0 && mac.digest.is_empty() => {
+                self.crypto_nak += 1;
+                true
+            },
+            Some(mac) if key.verify(&raw[..raw.len() - 4 - mac.digest.len()], mac) => {
+                self.valid += 1;
+                true
+            },
+            Some(_) => {
+                self.invalid += 1;
+                false
+            },
+            None => {
+                self.unsigned += 1;
+                true
+            },
+        }
+    }
+}
+
+#[test]
+fn parse_key_file() {
+    let keys = parse_keys("# comment\n1 MD5 secret\n2 SHA1 0123456789abcdef0123456789abcdef01234567 # hex\n3 SHA512 x\n").unwrap();
+    assert_eq!(keys.len(), 2);
+    assert_eq!(keys[0].secret, b"secret");
+    assert_eq!(keys[1].kind, KeyType::Sha1);
+    assert_eq!(keys[1].secret.len(), 20);
+    assert_eq!(keys[0].digest(b"abc").len(), 16);
+    assert!(keys[0].verify(b"abc", &keys[0].mac(b"abc")));
+    assert!(!keys[1].verify(b"abc", &keys[0].mac(b"abc")));
+}
+
+#[test]
+fn sign_requests() {
+    let key = &parse_keys("7 SHA1 secret").unwrap()[0];
+    let mut msg = NtpControlMessage::empty();
+    msg.opcode = 2;
+    msg.data = b"stratum".to_vec();
+    key.sign_control(&mut msg);
+    let raw = msg.pack();
+    assert_eq!(raw.len(), 24 + 4 + 20);
+    let parsed = NtpControlMessage::parse(&raw).unwrap();
+    assert_eq!(parsed.data, b"stratum");
+    assert!(key.verify(&raw[..24], parsed.mac.as_ref().unwrap()));
+
+    let mut msg = NtpdPrivatePacket::empty();
+    key.sign_private(&mut msg);
+    assert_eq!(msg.pack().len(), 192 + 4 + 20);
+}
+
+#[test]
+fn aes_cmac() {
+    // RFC 4493 example 2
+    let key = &parse_keys("1 AES128CMAC 2b7e151628aed2a6abf7158809cf4f3c").unwrap()[0];
+    let digest = key.digest(&[0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17, 0x2a]);
+    assert_eq!(digest, [0x07, 0x0a, 0x16, 0xb4, 0x6b, 0x4d, 0x41, 0x44, 0xf7, 0x9b, 0xdd, 0x9d, 0xd0, 0x4a, 0x28, 0x7c]);
+
+    let mut pkt = NTPPacket::empty();
+    pkt.mode = 3;
+    key.sign_client(&mut pkt);
+    let raw = pkt.pack();
+    assert_eq!(raw.len(), 48 + 4 + 16);
+    let mut stats = AuthStats::default();
+    assert!(stats.check(key, &raw, NTPPacket::parse(&raw).unwrap().mac.as_ref()));
+    assert_eq!(stats.verdict(), "authenticated");
+}
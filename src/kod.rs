@@ -0,0 +1,152 @@
+//! Kiss-o'-Death packets, see RFC 5905 section 7.4.
+//! A KoD is a mode 4 packet with stratum 0 where the refid holds an ascii "kiss code".
+use chrono::DateTime;
+use chrono::Local;
+
+use crate::packets::NTPPacket;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KissCode {
+    /// The association belongs to a unicast server
+    Acst,
+    /// Server authentication failed
+    Auth,
+    /// Autokey sequence failed
+    Auto,
+    /// The association belongs to a broadcast server
+    Bcst,
+    /// Cryptographic authentication or identification failed
+    Cryp,
+    /// Access denied by remote server
+    Deny,
+    /// Lost peer in symmetric mode
+    Drop,
+    /// Access denied due to local policy
+    Rstr,
+    /// The association has not yet synchronized for the first time
+    Init,
+    /// The association belongs to a dynamically discovered server
+    Mcst,
+    /// No key found
+    Nkey,
+    /// Network Time Security negative-acknowledgment (RFC 8915)
+    Ntsn,
+    /// Rate exceeded, the server has temporarily denied access
+    Rate,
+    /// Alteration of association from a remote host running ntpdc
+    Rmot,
+    /// A step change in system time has occurred
+    Step,
+    /// A code not defined in any RFC
+    Other([u8; 4]),
+}
+
+/// What the scanner does when it receives a KoD
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reaction {
+    /// Informational, the packet is processed like any other response
+    Continue,
+    /// Wait before sending anything else to the target and send slower afterwards
+    Backoff,
+    /// Give up on the current scan phase and start the next one
+    SkipPhase,
+    /// Stop scanning the target entirely
+    Abort,
+}
+
+impl KissCode {
+    pub fn from_refid(refid: &[u8; 4]) -> Self {
+        match refid {
+            b"ACST" => Self::Acst,
+            b"AUTH" => Self::Auth,
+            b"AUTO" => Self::Auto,
+            b"BCST" => Self::Bcst,
+            b"CRYP" => Self::Cryp,
+            b"DENY" => Self::Deny,
+            b"DROP" => Self::Drop,
+            b"RSTR" => Self::Rstr,
+            b"INIT" => Self::Init,
+            b"MCST" => Self::Mcst,
+            b"NKEY" => Self::Nkey,
+            b"NTSN" => Self::Ntsn,
+            b"RATE" => Self::Rate,
+            b"RMOT" => Self::Rmot,
+            b"STEP" => Self::Step,
+            other => Self::Other(*other),
+        }
+    }
+
+    pub fn from_packet(pkt: &NTPPacket) -> Option<Self> {
+        if pkt.mode == 4 && pkt.is_kod() {
+            Some(Self::from_refid(&pkt.refid))
+        } else {
+            None
+        }
+    }
+
+    pub fn reaction(&self) -> Reaction {
+        match self {
+            Self::Rate => Reaction::Backoff,
+            Self::Deny | Self::Rstr | Self::Drop => Reaction::Abort,
+            // the server won't answer this kind of request without (other) credentials
+            Self::Auth | Self::Auto | Self::Cryp | Self::Nkey | Self::Ntsn | Self::Rmot => Reaction::SkipPhase,
+            Self::Acst | Self::Bcst | Self::Mcst | Self::Init | Self::Step | Self::Other(_) => Reaction::Continue,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Self::Acst => "ACST".to_string(),
+            Self::Auth => "AUTH".to_string(),
+            Self::Auto => "AUTO".to_string(),
+            Self::Bcst => "BCST".to_string(),
+            Self::Cryp => "CRYP".to_string(),
+            Self::Deny => "DENY".to_string(),
+            Self::Drop => "DROP".to_string(),
+            Self::Rstr => "RSTR".to_string(),
+            Self::Init => "INIT".to_string(),
+            Self::Mcst => "MCST".to_string(),
+            Self::Nkey => "NKEY".to_string(),
+            Self::Ntsn => "NTSN".to_string(),
+            Self::Rate => "RATE".to_string(),
+            Self::Rmot => "RMOT".to_string(),
+            Self::Step => "STEP".to_string(),
+            Self::Other(bytes) => bytes.iter()
+                .map(|&b| if b.is_ascii_graphic() { (b as char).to_string() } else { format!("\\x{:02x}", b) })
+                .collect(),
+        }
+    }
+}
+
+/// A received KoD
+#[derive(Debug, Clone)]
+pub struct KodEvent {
+    pub code: KissCode,
+    pub time: DateTime<Local>,
+    /// the scan phase that was running when the KoD arrived
+    pub phase: &'static str,
+}
+
+impl KodEvent {
+    pub fn new(code: KissCode, phase: &'static str) -> Self {
+        Self {
+            code,
+            time: Local::now(),
+            phase,
+        }
+    }
+
+    /// `CODE@phase@time`, used in the csv output
+    pub fn csv_str(&self) -> String {
+        format!("{}@{}@{}", self.code.name(), self.phase, self.time.to_rfc3339())
+    }
+}
+
+#[test]
+fn kiss_codes() {
+    assert_eq!(KissCode::from_refid(b"RATE").reaction(), Reaction::Backoff);
+    assert_eq!(KissCode::from_refid(b"DENY").reaction(), Reaction::Abort);
+    assert_eq!(KissCode::from_refid(b"NKEY").reaction(), Reaction::SkipPhase);
+    assert_eq!(KissCode::from_refid(b"INIT").reaction(), Reaction::Continue);
+    assert_eq!(KissCode::from_refid(b"X\0\x01Y").name(), "X\\x00\\x01Y");
+}
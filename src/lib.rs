@@ -0,0 +1,45 @@
+//! Scanner for ntp servers, the library behind the `ntpscan` command.
+//!
+//! The packet codecs live in [packets], scans are set up with a [Scanner]:
+//!
+//! ```no_run
+//! let results = ntpscan::Scanner::new()
+//!     .target("192.0.2.1".parse().unwrap())
+//!     .probes(&["variables", "identify"])
+//!     .retries(2)
+//!     .start()
+//!     .unwrap();
+//! for res in results {
+//!     println!("{} {:?}", res.address, res.refid);
+//! }
+//! ```
+#[macro_use]
+pub mod log;
+mod send;
+pub mod socket;
+pub mod packets;
+pub mod identify;
+pub mod scan;
+pub mod scanner;
+pub mod dispatch;
+pub mod politeness;
+pub mod progress;
+pub mod stateless;
+#[cfg(feature = "async")]
+pub mod scan_async;
+pub mod monlist;
+pub mod variables;
+pub mod save;
+pub mod hierarchy;
+pub mod kod;
+pub mod probe;
+pub mod peers;
+pub mod mrulist;
+pub mod clock;
+pub mod mode7;
+pub mod keys;
+pub mod nts;
+pub mod chrony;
+
+pub use scan::ScanResult;
+pub use scanner::Scanner;
@@ -1,30 +1,29 @@
 #![feature(file_buffered)]
 use chrono::Local;
 use clap::Parser;
-use nix::sys::socket::SockaddrIn;
-use nix::sys::socket::SockaddrIn6;
-use socket::SockAddrInet;
+use std::collections::HashSet;
 use std::fs;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::Write;
-use std::str::FromStr;
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::Instant;
 
-use crate::scan::ScanResult;
+use ntpscan::hierarchy;
+use ntpscan::keys;
+use ntpscan::log;
+use ntpscan::nts;
+use ntpscan::packets;
+use ntpscan::probe;
+use ntpscan::progress::Progress;
+use ntpscan::save;
+use ntpscan::stateless::Sweep;
+use ntpscan::vprintln;
+use ntpscan::ScanResult;
+use ntpscan::Scanner;
 
-mod send;
-mod socket;
 mod args;
-// mod receive;
-mod packets;
-mod identify;
-mod scan;
-#[macro_use]
-mod log;
-mod monlist;
-mod variables;
-mod save;
 
 fn main() -> anyhow::Result<()> {
     let args = args::Args::parse();
@@ -34,58 +33,136 @@ fn main() -> anyhow::Result<()> {
     let targets: Box<dyn Iterator<Item = String>> = if args.target.is_some() {
         Box::new(args.target.as_ref().unwrap().iter().map(|s| s.clone()))
     } else {
-        let path = args.iplist.expect("Neither TARGET nor iplist is set");
+        let path = args.iplist.clone().expect("Neither TARGET nor iplist is set");
         let file = fs::File::open_buffered(path)?;
         Box::new(file.lines().map(|l| l.expect("malformed line")))
     };
 
-    eprintln!("saving csv to out.csv and variables_out.txt");
+    eprintln!("saving csv to out.csv, variables_out.txt, mrulist_out.txt and mode7_out.txt");
     let mut csv_out_file = File::create("out.csv").unwrap();
     let mut variables_out_file = File::create("variables_out.txt").unwrap();
+    let mut mrulist_out_file = File::create("mrulist_out.txt").unwrap();
+    let mut mode7_out_file = File::create("mode7_out.txt").unwrap();
 
     // convert addresses
-    let addresses: Vec<SockAddrInet> = targets.map(|target| {
-        let addr: socket::SockAddrInet;
-        if let Ok(addr4) = SockaddrIn::from_str(&format!("{target}:123")) {
-            addr = SockAddrInet::IPv4(addr4)
-        } else if let Ok(addr6) = SockaddrIn6::from_str(&format!("[{target}]:123")) {
-            addr = SockAddrInet::IPv6(addr6)
-        } else {
-            panic!("Invalid IPv4 or Ipv6 {target}");
-        };
-        addr
-    }).collect();
+    let addresses = targets.map(|target| target.parse::<IpAddr>()
+        .map_err(|_| anyhow::anyhow!("Invalid IPv4 or Ipv6 {target}")))
+        .collect::<anyhow::Result<Vec<IpAddr>>>()?;
+
+    // only scan the targets that answer the sweep
+    let addresses = if args.stateless {
+        let mut responders = HashSet::new();
+        Sweep::new()
+            .targets(addresses.iter().copied())
+            .rate(args.stateless_rate)
+            .run(|hit| {
+                println!("{}", hit.summary());
+                responders.insert(hit.address.ip());
+            })?;
+        eprintln!("{} targets responded to the stateless sweep", responders.len());
+        // in the order they were given, once each
+        addresses.into_iter().filter(|ip| responders.remove(ip)).collect()
+    } else {
+        addresses
+    };
 
-    let start_time = Instant::now();
+    let mut probe_names = args.probes.clone();
+    if !args.identify {
+        probe_names.retain(|p| p != "identify");
+    }
 
-    let targets_p_thread = addresses.len().div_ceil(args.threads.into());
+    let mut client_template = match args.client_template {
+        args::ClientTemplate::Zero => probe::ProbeConfig::default().client_template,
+        args::ClientTemplate::Nmap => packets::NTPPacket::parse(packets::NMAP_CLIENT_MODE).expect("invalid nmap template"),
+    };
+    if let Some(poll) = args.client_poll {
+        client_template.poll = poll;
+    }
+    if let Some(precision) = args.client_precision {
+        client_template.precision = precision;
+    }
+    if let Some(stratum) = args.client_stratum {
+        client_template.stratum = stratum;
+    }
+    let keys = match &args.keys {
+        Some(path) => keys::read_keys(path)?,
+        None => vec![],
+    };
+    let control_key = match args.control_key {
+        Some(id) => Some(keys.iter().find(|k| k.id == id).cloned()
+            .ok_or_else(|| anyhow::anyhow!("control key {} not found", id))?),
+        None => None,
+    };
+    let client_key = match args.client_key {
+        Some(id) => Some(keys.iter().find(|k| k.id == id).cloned()
+            .ok_or_else(|| anyhow::anyhow!("client key {} not found", id))?),
+        None => None,
+    };
 
-    let mut receivers = vec![];
+    let probe_config = probe::ProbeConfig {
+        identify_versions: args.identify_versions.clone(),
+        client_template,
+        mode6_versions: args.mode6_versions.clone(),
+        mode7_versions: args.mode7_versions.clone(),
+        control_key,
+        client_key,
+        nts_ke_port: args.nts_ke_port,
+        nts_pool: Arc::new(nts::NtsPool::new(args.nts_workers)),
+    };
 
-    for chunk in addresses.chunks(targets_p_thread) {
-        let rx = scan::start_thread(chunk.to_vec(), args.retries, args.targets_per_thread, args.poll, args.spread, args.identify);
-        receivers.push(rx);
-    }
+    let start_time = Instant::now();
 
-    vprintln!("Scanning {} targets using {} threads each scanning at most {} targets concurrently", addresses.len(), receivers.len(), args.targets_per_thread);
+    let progress = args.progress.then(|| Progress::new(addresses.len()));
+
+    let results = Scanner::new()
+        .targets(addresses)
+        .threads(args.threads.into())
+        .retries(args.retries)
+        .concurrency(args.targets_per_thread)
+        .poll_timeout(args.poll)
+        .min_timeout(args.min_timeout)
+        .spread(args.spread)
+        .source_port(args.source_port)
+        .prefix_lengths(args.prefix_v4, args.prefix_v6)
+        .targets_per_prefix(args.targets_per_prefix)
+        .prefix_rate(args.prefix_rate)
+        .progress(progress.clone())
+        .probes(&probe_names.iter().map(String::as_str).collect::<Vec<&str>>())
+        .parallel_probes(args.parallel_probes)
+        .probe_config(probe_config)
+        .start()?;
 
     csv_out_file.write(ScanResult::csv_header().as_bytes()).expect("failed to write csv header");
 
-    loop {
-        receivers.retain(|rx| {
-            match rx.recv() {
-                Ok(res) => {
-                    save::save_result(res, &mut csv_out_file, &mut variables_out_file);
-                    true
-                },
-                Err(_) => {
-                    false
-                },
-            }
-        });
-        if receivers.is_empty() {
-            break;
+    let mut hierarchy = args.hierarchy.as_ref().map(|_| hierarchy::Hierarchy::new());
+
+    if let Some(progress) = &progress {
+        progress.report();
+    }
+
+    for res in results {
+        let mut save = || save::save_result(&res, &mut csv_out_file, &mut variables_out_file, &mut mrulist_out_file, &mut mode7_out_file);
+        match &progress {
+            Some(progress) => progress.interrupt(save),
+            None => save(),
         }
+        if let Some(hierarchy) = hierarchy.as_mut() {
+            hierarchy.insert_result(&res);
+        }
+    }
+
+    if let Some(progress) = &progress {
+        progress.stop();
+    }
+
+    if let Some(mut hierarchy) = hierarchy {
+        hierarchy.link();
+        let graph = match args.hierarchy_format {
+            args::GraphFormat::Dot => hierarchy.dot(),
+            args::GraphFormat::Graphml => hierarchy.graphml(),
+        };
+        fs::write(args.hierarchy.as_ref().unwrap(), graph)?;
+        print!("{}", hierarchy.report());
     }
 
     println!("Scan ended on {} after {}s", Local::now().format("%A %B %d %Y at %H:%M:%S"), start_time.elapsed().as_secs());
@@ -0,0 +1,589 @@
+//! Information requests of the xntpd mode 7 (private) protocol besides monlist.
+//! Their answers disclose a lot about the server, and several of them are used for DDoS reflection.
+//!
+//! The item structures are those of `ntp_request.h`,
+//! the IPv6 fields are only present in responses of [IMPL_XNTPD](packets::private::IMPL_XNTPD).
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+
+use crate::keys::Key;
+use crate::monlist::Amplification;
+use crate::packets::private;
+use crate::packets::AnyNTPPacket;
+use crate::packets::NtpdPrivatePacket;
+use crate::probe::Probe;
+use crate::probe::ProbeConfig;
+use crate::scan::ScanResult;
+use crate::scan::ScanState;
+use crate::scan::ScanTypeStatus;
+
+/// A decoded mode 7 item
+#[derive(Debug, Clone)]
+pub enum Mode7Item {
+    /// `info_peer_list`
+    PeerList {
+        addr: IpAddr,
+        port: u16,
+        hmode: u8,
+        flags: u8,
+    },
+    /// `info_peer_summary`
+    PeerSummary {
+        dstadr: IpAddr,
+        srcadr: IpAddr,
+        srcport: u16,
+        stratum: u8,
+        hpoll: i8,
+        ppoll: i8,
+        reach: u8,
+        flags: u8,
+        hmode: u8,
+        /// seconds
+        delay: f64,
+        /// seconds
+        offset: f64,
+        /// seconds
+        dispersion: f64,
+    },
+    /// `info_sys`
+    SysInfo {
+        peer: IpAddr,
+        peer_mode: u8,
+        leap: u8,
+        stratum: u8,
+        precision: i8,
+        rootdelay: f64,
+        rootdispersion: f64,
+        refid: [u8; 4],
+        reftime: u64,
+        poll: u32,
+        flags: u8,
+        /// ppm
+        frequency: f64,
+        /// ppm
+        stability: f64,
+    },
+    /// `info_loop`
+    LoopInfo {
+        last_offset: f64,
+        drift_comp: f64,
+        compliance: u32,
+        watchdog_timer: u32,
+    },
+    /// `info_if_stats`
+    IfStats {
+        addr: IpAddr,
+        bcast: IpAddr,
+        mask: IpAddr,
+        name: String,
+        flags: i32,
+        received: i32,
+        sent: i32,
+        notsent: i32,
+        uptime: i32,
+        peercnt: u32,
+    },
+    /// counter structures like `info_sys_stats`, as name and value
+    Counters(Vec<(&'static str, u32)>),
+    /// an item of an unknown size
+    Raw(Vec<u8>),
+}
+
+impl Mode7Item {
+    /// `name=value` pairs, used in the mode7 out file
+    pub fn summary(&self) -> String {
+        match self {
+            Self::PeerList { addr, port, hmode, flags } =>
+                format!("addr={} port={} hmode={} flags={:#x}", addr, port, hmode, flags),
+            Self::PeerSummary { dstadr, srcadr, srcport, stratum, hpoll, ppoll, reach, flags, hmode, delay, offset, dispersion } =>
+                format!("dstadr={} srcadr={} srcport={} stratum={} hpoll={} ppoll={} reach={:#o} flags={:#x} hmode={} delay={} offset={} dispersion={}",
+                    dstadr, srcadr, srcport, stratum, hpoll, ppoll, reach, flags, hmode, delay, offset, dispersion),
+            Self::SysInfo { peer, peer_mode, leap, stratum, precision, rootdelay, rootdispersion, refid, reftime, poll, flags, frequency, stability } =>
+                format!("peer={} peer_mode={} leap={} stratum={} precision={} rootdelay={} rootdispersion={} refid={} reftime={:#x} poll={} flags={:#x} frequency={} stability={}",
+                    peer, peer_mode, leap, stratum, precision, rootdelay, rootdispersion,
+                    refid.iter().map(|b| format!("{:02x}", b)).collect::<String>(), reftime, poll, flags, frequency, stability),
+            Self::LoopInfo { last_offset, drift_comp, compliance, watchdog_timer } =>
+                format!("last_offset={} drift_comp={} compliance={} watchdog_timer={}", last_offset, drift_comp, compliance, watchdog_timer),
+            Self::IfStats { addr, bcast, mask, name, flags, received, sent, notsent, uptime, peercnt } =>
+                format!("name={} addr={} bcast={} mask={} flags={:#x} received={} sent={} notsent={} uptime={} peercnt={}",
+                    name, addr, bcast, mask, flags, received, sent, notsent, uptime, peercnt),
+            Self::Counters(fields) => fields.iter().map(|(n, v)| format!("{}={}", n, v)).collect::<Vec<String>>().join(" "),
+            Self::Raw(bytes) => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+}
+
+/// The error code of a mode 7 response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode7Error {
+    /// the implementation code is not supported
+    Impl,
+    /// the request code is not implemented
+    Req,
+    /// the request was malformed
+    Fmt,
+    /// the requested data is not available, like the monlist when monitoring is disabled
+    NoData,
+    /// the request requires authentication
+    Auth,
+    Other(u16),
+}
+
+impl Mode7Error {
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            0 => None,
+            c if c == private::INFO_ERR_IMPL => Some(Self::Impl),
+            c if c == private::INFO_ERR_REQ => Some(Self::Req),
+            c if c == private::INFO_ERR_FMT => Some(Self::Fmt),
+            c if c == private::INFO_ERR_NODATA => Some(Self::NoData),
+            c if c == private::INFO_ERR_AUTH => Some(Self::Auth),
+            other => Some(Self::Other(other)),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Self::Impl => "IMPL".to_string(),
+            Self::Req => "REQ".to_string(),
+            Self::Fmt => "FMT".to_string(),
+            Self::NoData => "NODATA".to_string(),
+            Self::Auth => "AUTH".to_string(),
+            Self::Other(code) => format!("ERR{}", code),
+        }
+    }
+}
+
+/// The implementation codes every request is sent with, the accepted ones are recorded in [Mode7Status]
+pub static IMPL_CODES: [u8; 3] = [private::IMPL_XNTPD, private::IMPL_XNTPD_OLD, private::IMPL_UNIV];
+
+pub fn implementation_name(code: u8) -> String {
+    match code {
+        c if c == private::IMPL_UNIV => "UNIV".to_string(),
+        c if c == private::IMPL_XNTPD_OLD => "XNTPD_OLD".to_string(),
+        c if c == private::IMPL_XNTPD => "XNTPD".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// How a target handles a mode 7 request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode7Access {
+    /// no mode 7 response at all, like ntpd 4.2.8 without `enable mode7` or `restrict noquery`
+    Disabled,
+    /// mode 7 is answered, but only with errors
+    Restricted,
+    /// the request was answered with data
+    Enabled,
+}
+
+/// What the responses to a mode 7 request tell about the target
+#[derive(Debug, Clone, Default)]
+pub struct Mode7Status {
+    /// implementation codes answered with something other than INFO_ERR_IMPL
+    pub implementations: Vec<u8>,
+    /// every distinct error received
+    pub errors: Vec<Mode7Error>,
+    /// whether any response was received
+    pub responded: bool,
+    /// whether any response without an error was received
+    pub answered: bool,
+}
+
+impl Mode7Status {
+    /// Account a response, returns its error
+    pub fn record(&mut self, pkt: &NtpdPrivatePacket) -> Option<Mode7Error> {
+        self.responded = true;
+        let error = Mode7Error::from_code(pkt.error);
+        if error != Some(Mode7Error::Impl) && !self.implementations.contains(&pkt.implementation) {
+            self.implementations.push(pkt.implementation);
+        }
+        match error {
+            Some(error) if !self.errors.contains(&error) => self.errors.push(error),
+            Some(_) => {},
+            None => self.answered = true,
+        }
+        error
+    }
+
+    pub fn access(&self) -> Mode7Access {
+        if self.answered {
+            Mode7Access::Enabled
+        } else if self.responded {
+            Mode7Access::Restricted
+        } else {
+            Mode7Access::Disabled
+        }
+    }
+
+    /// like `restricted(AUTH)` or `enabled`, the implementations are not included
+    pub fn summary(&self) -> String {
+        match self.access() {
+            Mode7Access::Disabled => "disabled".to_string(),
+            Mode7Access::Enabled => "enabled".to_string(),
+            Mode7Access::Restricted => format!("restricted({})",
+                self.errors.iter().map(|e| e.name()).collect::<Vec<String>>().join("/")),
+        }
+    }
+
+    pub fn implementations_str(&self) -> String {
+        self.implementations.iter().map(|&i| implementation_name(i)).collect::<Vec<String>>().join("/")
+    }
+}
+
+/// The response to one of the mode 7 requests
+#[derive(Debug, Clone)]
+pub struct Mode7Answer {
+    /// name of the probe
+    pub name: &'static str,
+    pub status: Mode7Status,
+    pub items: Vec<Mode7Item>,
+    pub amplification: Amplification,
+}
+
+static SYS_STATS_FIELDS: &[&str] = &["timeup", "timereset", "denied", "oldversionpkt", "newversionpkt",
+    "unknownversion", "badlength", "processed", "badauth", "received", "limitrejected", "lamport", "tsrounding"];
+
+static MEM_STATS_FIELDS: &[&str] = &["findpeer_calls", "allocations", "demobilizations"];
+
+static IO_STATS_FIELDS: &[&str] = &["dropped", "ignored", "received", "sent", "notsent", "interrupts", "int_received"];
+
+fn u16_at(item: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([item[at], item[at + 1]])
+}
+
+fn u32_at(item: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(item[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(item: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(item[at..at + 8].try_into().unwrap())
+}
+
+/// signed 16.16 fixed point
+fn s_fp(item: &[u8], at: usize) -> f64 {
+    u32_at(item, at) as i32 as f64 / 65536.0
+}
+
+/// unsigned 16.16 fixed point
+fn u_fp(item: &[u8], at: usize) -> f64 {
+    u32_at(item, at) as f64 / 65536.0
+}
+
+/// signed 32.32 fixed point
+fn l_fp(item: &[u8], at: usize) -> f64 {
+    u64_at(item, at) as i64 as f64 / 4294967296.0
+}
+
+fn ipv4_at(item: &[u8], at: usize) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::from(u32_at(item, at)))
+}
+
+/// Pick the IPv4 or IPv6 address depending on the v6 flag, if the item has the IPv6 fields
+fn addr(item: &[u8], v4: usize, v6_flag: usize, v6: usize) -> IpAddr {
+    if item.len() >= v6 + 16 && u32_at(item, v6_flag) != 0 {
+        IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&item[v6..v6 + 16]).unwrap()))
+    } else {
+        ipv4_at(item, v4)
+    }
+}
+
+/// The `union addrun` of `info_if_stats`
+fn addrun(item: &[u8], at: usize, v6: bool) -> IpAddr {
+    if v6 {
+        IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&item[at..at + 16]).unwrap()))
+    } else {
+        ipv4_at(item, at)
+    }
+}
+
+fn counters(item: &[u8], start: usize, names: &[&'static str]) -> Vec<(&'static str, u32)> {
+    names.iter()
+        .enumerate()
+        .take_while(|(i, _)| start + i * 4 + 4 <= item.len())
+        .map(|(i, name)| (*name, u32_at(item, start + i * 4)))
+        .collect()
+}
+
+/// Decode a single item of a response to the given request code
+pub fn decode_item(reqcode: u8, item: &[u8]) -> Mode7Item {
+    match reqcode {
+        r if r == private::REQ_PEER_LIST && item.len() >= 8 => Mode7Item::PeerList {
+            addr: addr(item, 0, 8, 16),
+            port: u16_at(item, 4),
+            hmode: item[6],
+            flags: item[7],
+        },
+        r if r == private::REQ_PEER_LIST_SUM && item.len() >= 32 => Mode7Item::PeerSummary {
+            dstadr: addr(item, 0, 32, 40),
+            srcadr: addr(item, 4, 32, 56),
+            srcport: u16_at(item, 8),
+            stratum: item[10],
+            hpoll: item[11] as i8,
+            ppoll: item[12] as i8,
+            reach: item[13],
+            flags: item[14],
+            hmode: item[15],
+            delay: s_fp(item, 16),
+            offset: l_fp(item, 20),
+            dispersion: u_fp(item, 28),
+        },
+        r if r == private::REQ_SYS_INFO && item.len() >= 56 => Mode7Item::SysInfo {
+            peer: addr(item, 0, 56, 64),
+            peer_mode: item[4],
+            leap: item[5],
+            stratum: item[6],
+            precision: item[7] as i8,
+            rootdelay: s_fp(item, 8),
+            rootdispersion: u_fp(item, 12),
+            refid: item[16..20].try_into().unwrap(),
+            reftime: u64_at(item, 20),
+            poll: u32_at(item, 28),
+            flags: item[32],
+            frequency: s_fp(item, 40),
+            stability: u_fp(item, 52),
+        },
+        r if r == private::REQ_SYS_STATS => Mode7Item::Counters(counters(item, 0, SYS_STATS_FIELDS)),
+        r if r == private::REQ_IO_STATS && item.len() >= 12 => {
+            let mut fields = counters(item, 12, IO_STATS_FIELDS);
+            fields.splice(0..0, [
+                ("timereset", u32_at(item, 0)),
+                ("totalrecvbufs", u16_at(item, 4) as u32),
+                ("freerecvbufs", u16_at(item, 6) as u32),
+                ("fullrecvbufs", u16_at(item, 8) as u32),
+                ("lowwater", u16_at(item, 10) as u32),
+            ]);
+            Mode7Item::Counters(fields)
+        },
+        r if r == private::REQ_MEM_STATS && item.len() >= 8 => {
+            let mut fields = counters(item, 8, MEM_STATS_FIELDS);
+            fields.splice(0..0, [
+                ("timereset", u32_at(item, 0)),
+                ("totalpeermem", u16_at(item, 4) as u32),
+                ("freepeermem", u16_at(item, 6) as u32),
+            ]);
+            Mode7Item::Counters(fields)
+        },
+        r if r == private::REQ_LOOP_INFO && item.len() >= 24 => Mode7Item::LoopInfo {
+            last_offset: l_fp(item, 0),
+            drift_comp: l_fp(item, 8),
+            compliance: u32_at(item, 16),
+            watchdog_timer: u32_at(item, 20),
+        },
+        r if r == private::REQ_IF_STATS && item.len() >= 128 => {
+            let v6 = u32_at(item, 48) != 0;
+            Mode7Item::IfStats {
+                addr: addrun(item, 0, v6),
+                bcast: addrun(item, 16, v6),
+                mask: addrun(item, 32, v6),
+                name: String::from_utf8_lossy(&item[52..84]).trim_end_matches('\0').to_string(),
+                flags: u32_at(item, 84) as i32,
+                received: u32_at(item, 96) as i32,
+                sent: u32_at(item, 100) as i32,
+                notsent: u32_at(item, 104) as i32,
+                uptime: u32_at(item, 108) as i32,
+                peercnt: u32_at(item, 124),
+            }
+        },
+        _ => Mode7Item::Raw(item.to_vec()),
+    }
+}
+
+/// Split the items of a response and decode them
+pub fn decode_items(pkt: &NtpdPrivatePacket) -> Vec<Mode7Item> {
+    let size = (pkt.size & 0xfff) as usize;
+    if size == 0 {
+        return vec![];
+    }
+    pkt.items.chunks_exact(size)
+        .take(pkt.nitems as usize)
+        .map(|item| decode_item(pkt.reqcode, item))
+        .collect()
+}
+
+/// Sends a single mode 7 request code
+pub struct Mode7Probe {
+    name: &'static str,
+    reqcode: u8,
+    retries: u32,
+    versions: Vec<u8>,
+    key: Option<Key>,
+    status: Mode7Status,
+    /// the implementation code whose items are kept
+    data_impl: Option<u8>,
+    items: Vec<Mode7Item>,
+    amplification: Amplification,
+    /// requests of the last round whose response has not ended yet
+    outstanding: usize,
+}
+
+impl Mode7Probe {
+    pub fn new(config: &ProbeConfig, name: &'static str, reqcode: u8) -> Self {
+        Self {
+            name,
+            reqcode,
+            retries: 0,
+            versions: config.mode7_versions.clone(),
+            key: config.control_key.clone(),
+            status: Mode7Status::default(),
+            data_impl: None,
+            items: vec![],
+            amplification: Amplification::default(),
+            outstanding: 0,
+        }
+    }
+
+    fn queue_requests(&mut self, state: &mut ScanState) {
+        for &version in &self.versions {
+            for impl_code in IMPL_CODES {
+                let mut msg = NtpdPrivatePacket::empty();
+                msg.version = version;
+                msg.implementation = impl_code;
+                msg.reqcode = self.reqcode;
+                if let Some(key) = &self.key {
+                    key.sign_private(&mut msg);
+                }
+                self.amplification.request_bytes += msg.pack().len();
+                state.queue.push_back(AnyNTPPacket::Private(msg));
+            }
+        }
+        self.outstanding = self.versions.len() * IMPL_CODES.len();
+    }
+
+    /// A response ended, done once every request of the round was answered
+    fn ended(&mut self) -> ScanTypeStatus {
+        self.outstanding = self.outstanding.saturating_sub(1);
+        if self.outstanding == 0 {
+            ScanTypeStatus::Done
+        } else {
+            ScanTypeStatus::Continue
+        }
+    }
+}
+
+impl Probe for Mode7Probe {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn init(&mut self, state: &mut ScanState) {
+        self.queue_requests(state);
+    }
+
+    fn accepts(&self, pkt: &AnyNTPPacket) -> bool {
+        matches!(pkt, AnyNTPPacket::Private(p) if p.reqcode == self.reqcode)
+    }
+
+    fn receive(&mut self, state: &mut ScanState, pkt: &AnyNTPPacket) -> ScanTypeStatus {
+        let AnyNTPPacket::Private(pkt) = pkt else {
+            vprintln!("{} (mode 7) {} request received non-private mode response", state.address, self.name);
+            return ScanTypeStatus::Continue;
+        };
+        if pkt.reqcode != self.reqcode {
+            vprintln!("{} (mode 7) {} request received a response with a different reqcode {:x?}", state.address, self.name, pkt.reqcode);
+            return ScanTypeStatus::Continue;
+        }
+        if !pkt.response {
+            vprintln!("{} (mode 7) received private request instead of response, quitting", state.address);
+            return ScanTypeStatus::Done;
+        }
+        if let Some(error) = self.status.record(pkt) {
+            vprintln!("{} received {} response with error {} (implementation {})",
+                state.address, self.name, error.name(), implementation_name(pkt.implementation));
+            return self.ended();
+        }
+
+        // the responses to every implementation code count for the amplification
+        self.amplification.responses += 1;
+        self.amplification.response_bytes += state.last_pkt_len;
+        if *self.data_impl.get_or_insert(pkt.implementation) == pkt.implementation {
+            // several implementation codes may be answered, the items of the first one are kept
+            self.items.extend(decode_items(pkt));
+            vprintln!("{} received {} response ({} items)", state.address, self.name, pkt.nitems);
+        }
+
+        if pkt.more {
+            ScanTypeStatus::Continue
+        } else {
+            self.ended()
+        }
+    }
+
+    fn timeout(&mut self, state: &mut ScanState) -> ScanTypeStatus {
+        // a server that answered with an error won't change its mind
+        if !self.status.responded && self.retries < state.maxretries {
+            self.queue_requests(state);
+            self.retries += 1;
+            ScanTypeStatus::Continue
+        } else {
+            vprintln!("{} (mode 7) {} timed out", state.address, self.name);
+            ScanTypeStatus::Done
+        }
+    }
+
+    fn contribute(&self, result: &mut ScanResult) {
+        if self.status.responded {
+            result.mode7.push(Mode7Answer {
+                name: self.name,
+                status: self.status.clone(),
+                items: self.items.clone(),
+                amplification: self.amplification.clone(),
+            });
+        }
+    }
+}
+
+#[test]
+fn decode_sys_info() {
+    let mut item = vec![0u8; 80];
+    item[0..4].copy_from_slice(&[192, 0, 2, 1]);
+    item[4] = 3;
+    item[6] = 2;
+    item[7] = 0xe9;
+    item[8..12].copy_from_slice(&0x0000_8000u32.to_be_bytes());
+    item[16..20].copy_from_slice(b"GPS\0");
+    let Mode7Item::SysInfo { peer, stratum, precision, rootdelay, refid, .. } = decode_item(private::REQ_SYS_INFO, &item) else {
+        panic!("not decoded as sys info");
+    };
+    assert_eq!(peer, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+    assert_eq!(stratum, 2);
+    assert_eq!(precision, -23);
+    assert_eq!(rootdelay, 0.5);
+    assert_eq!(&refid, b"GPS\0");
+}
+
+#[test]
+fn decode_peer_list_items() {
+    let mut pkt = NtpdPrivatePacket::empty();
+    pkt.reqcode = private::REQ_PEER_LIST;
+    pkt.nitems = 2;
+    pkt.size = 32;
+    let mut v4 = vec![0u8; 32];
+    v4[0..4].copy_from_slice(&[192, 0, 2, 1]);
+    v4[4..6].copy_from_slice(&123u16.to_be_bytes());
+    let mut v6 = vec![0u8; 32];
+    v6[8..12].copy_from_slice(&1u32.to_be_bytes());
+    v6[16..32].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+    pkt.items = [v4, v6].concat();
+    let items = decode_items(&pkt);
+    assert!(matches!(items[0], Mode7Item::PeerList { addr: IpAddr::V4(_), port: 123, .. }));
+    assert!(matches!(items[1], Mode7Item::PeerList { addr: IpAddr::V6(_), .. }));
+}
+
+#[test]
+fn mode7_status() {
+    let mut status = Mode7Status::default();
+    assert_eq!(status.access(), Mode7Access::Disabled);
+    let mut pkt = NtpdPrivatePacket::empty();
+    pkt.response = true;
+    pkt.implementation = private::IMPL_XNTPD_OLD;
+    pkt.error = private::INFO_ERR_IMPL;
+    assert_eq!(status.record(&pkt), Some(Mode7Error::Impl));
+    pkt.implementation = private::IMPL_XNTPD;
+    pkt.error = private::INFO_ERR_NODATA;
+    assert_eq!(status.record(&pkt), Some(Mode7Error::NoData));
+    assert_eq!(status.access(), Mode7Access::Restricted);
+    assert_eq!(status.summary(), "restricted(IMPL/NODATA)");
+    assert_eq!(status.implementations_str(), "XNTPD");
+}
@@ -1,71 +1,177 @@
+use crate::keys::Key;
+use crate::mode7::implementation_name;
+use crate::mode7::IMPL_CODES;
+use crate::mode7::Mode7Status;
 use crate::packets;
 use crate::packets::AnyNTPPacket;
-use crate::packets::NtpControlMessage;
 use crate::packets::NtpdPrivatePacket;
+use crate::probe::Probe;
+use crate::probe::ProbeConfig;
+use crate::scan::ScanResult;
 use crate::scan::ScanState;
 use crate::scan::ScanTypeStatus;
-use crate::variables::Mode6Variables;
 
-pub struct MonlistRequestStatus {
+/// Sends the mode 7 monlist request for every implementation code
+pub struct MonlistProbe {
     retries: u32,
+    supports_monlist: bool,
+    status: Mode7Status,
+    versions: Vec<u8>,
+    key: Option<Key>,
+    /// amount of monlist entries received in the response that is kept
+    entries: usize,
+    /// the implementation and request code whose entries are counted
+    data_request: Option<(u8, u8)>,
+    amplification: Amplification,
+    /// requests of the last round whose response has not ended yet
+    outstanding: usize,
 }
 
-impl MonlistRequestStatus {
-    pub fn new() -> Self {
-        Self {
-            retries: 0
-        }
-    }
+/// How many bytes a server sends in response to our request(s),
+/// the reason monlist and friends are used for DDoS reflection
+#[derive(Debug, Clone, Default)]
+pub struct Amplification {
+    /// bytes of every request sent in the phase, retries included
+    pub request_bytes: usize,
+    pub responses: usize,
+    pub response_bytes: usize,
 }
 
-pub fn init(state: &mut ScanState) {
-    let impl_codes = [packets::private::IMPL_XNTPD, packets::private::IMPL_XNTPD_OLD];
-    let reqcodes = [packets::private::REQ_MON_GETLIST, packets::private::REQ_MON_GETLIST_1];
-    for impl_code in impl_codes {
-        for reqcode in reqcodes {
-            let mut msg = NtpdPrivatePacket::empty();
-            msg.version = 2;
-            msg.implementation = impl_code;
-            msg.reqcode = reqcode;
-            state.queue.push_back(AnyNTPPacket::Private(msg));
+impl Amplification {
+    /// the bandwidth amplification factor
+    pub fn factor(&self) -> f64 {
+        if self.request_bytes == 0 {
+            return 0.0;
         }
+        self.response_bytes as f64 / self.request_bytes as f64
     }
 }
 
-pub fn receive(state: &mut ScanState, pkt: &AnyNTPPacket) -> ScanTypeStatus {
-    match pkt {
-        AnyNTPPacket::Private(pkt) => {
+impl MonlistProbe {
+    pub fn new(config: &ProbeConfig) -> Self {
+        Self {
+            retries: 0,
+            supports_monlist: false,
+            status: Mode7Status::default(),
+            versions: config.mode7_versions.clone(),
+            key: config.control_key.clone(),
+            entries: 0,
+            data_request: None,
+            amplification: Amplification::default(),
+            outstanding: 0,
+        }
+    }
 
-            if pkt.reqcode != crate::packets::private::REQ_MON_GETLIST_1 && pkt.reqcode != crate::packets::private::REQ_MON_GETLIST  {
-                vprintln!("{} (mode 7) monlist request received a mode 7 response with a different reqcode {:x?}", state.address, pkt.reqcode);
-            } else if pkt.response != true {
-                vprintln!("{} (mode 7) received private request instead of response, quitting", state.address);
-                // it might've just echo'd our request
-                return ScanTypeStatus::Done
+    fn queue_requests(&mut self, state: &mut ScanState) {
+        let reqcodes = [packets::private::REQ_MON_GETLIST, packets::private::REQ_MON_GETLIST_1];
+        for &version in &self.versions {
+            for impl_code in IMPL_CODES {
+                for reqcode in reqcodes {
+                    let mut msg = NtpdPrivatePacket::empty();
+                    msg.version = version;
+                    msg.implementation = impl_code;
+                    msg.reqcode = reqcode;
+                    if let Some(key) = &self.key {
+                        key.sign_private(&mut msg);
+                    }
+                    self.amplification.request_bytes += msg.pack().len();
+                    state.queue.push_back(AnyNTPPacket::Private(msg));
+                }
             }
-            else if pkt.error != 0 {
-                vprintln!("{} received monlist response with error", state.address);
-            } else {
-                state.supports_monlist = true;
-                vprintln!("{} received monlist response!!! ({} items)", state.address, pkt.nitems);
-                return ScanTypeStatus::Done;
-            }
-        },
-        _other => {
-            vprintln!("{} (mode 7) monlist request received non-private mode response", state.address)
         }
+        self.outstanding = self.versions.len() * IMPL_CODES.len() * reqcodes.len();
     }
 
-    ScanTypeStatus::Continue
+    /// A response ended, done once every request of the round was answered
+    fn ended(&mut self) -> ScanTypeStatus {
+        self.outstanding = self.outstanding.saturating_sub(1);
+        if self.outstanding == 0 {
+            ScanTypeStatus::Done
+        } else {
+            ScanTypeStatus::Continue
+        }
+    }
 }
 
-pub fn timeout(state: &mut ScanState) -> ScanTypeStatus {
-    if state.monlist_request_status.retries < state.maxretries {
-        init(state);
-        state.monlist_request_status.retries += 1;
+impl Probe for MonlistProbe {
+    fn name(&self) -> &'static str {
+        "monlist"
+    }
+
+    fn init(&mut self, state: &mut ScanState) {
+        self.queue_requests(state);
+    }
+
+    fn accepts(&self, pkt: &AnyNTPPacket) -> bool {
+        match pkt {
+            AnyNTPPacket::Private(pkt) => pkt.reqcode == packets::private::REQ_MON_GETLIST || pkt.reqcode == packets::private::REQ_MON_GETLIST_1,
+            _ => false,
+        }
+    }
+
+    fn receive(&mut self, state: &mut ScanState, pkt: &AnyNTPPacket) -> ScanTypeStatus {
+        match pkt {
+            AnyNTPPacket::Private(pkt) => {
+
+                if pkt.reqcode != crate::packets::private::REQ_MON_GETLIST_1 && pkt.reqcode != crate::packets::private::REQ_MON_GETLIST  {
+                    vprintln!("{} (mode 7) monlist request received a mode 7 response with a different reqcode {:x?}", state.address, pkt.reqcode);
+                } else if pkt.response != true {
+                    vprintln!("{} (mode 7) received private request instead of response, quitting", state.address);
+                    // it might've just echo'd our request
+                    return ScanTypeStatus::Done
+                }
+                else if let Some(error) = self.status.record(pkt) {
+                    vprintln!("{} received monlist response with error {} (implementation {})",
+                        state.address, error.name(), implementation_name(pkt.implementation));
+                    return self.ended();
+                } else {
+                    self.supports_monlist = true;
+                    // the responses to every request count for the amplification
+                    self.amplification.responses += 1;
+                    self.amplification.response_bytes += state.last_pkt_len;
+                    let request = (pkt.implementation, pkt.reqcode);
+                    if *self.data_request.get_or_insert(request) == request {
+                        self.entries += pkt.nitems as usize;
+                    }
+                    vprintln!("{} received monlist response!!! ({} items)", state.address, pkt.nitems);
+                    // keep receiving until the last packet of every response
+                    if !pkt.more {
+                        return self.ended();
+                    }
+                }
+            },
+            _other => {
+                vprintln!("{} (mode 7) monlist request received non-private mode response", state.address)
+            }
+        }
+
         ScanTypeStatus::Continue
-    } else {
-        vprintln!("{} (mode 7) monlist timed out", state.address);
-        ScanTypeStatus::Done
+    }
+
+    fn timeout(&mut self, state: &mut ScanState) -> ScanTypeStatus {
+        // a server that answered with an error won't change its mind
+        if !self.status.responded && self.retries < state.maxretries {
+            self.queue_requests(state);
+            self.retries += 1;
+            ScanTypeStatus::Continue
+        } else {
+            vprintln!("{} (mode 7) monlist timed out", state.address);
+            ScanTypeStatus::Done
+        }
+    }
+
+    fn finalise(&mut self, state: &mut ScanState) {
+        if self.supports_monlist {
+            vprintln!("{} monlist returned {} entries in {} bytes, amplification factor {:.1}",
+                state.address, self.entries, self.amplification.response_bytes, self.amplification.factor());
+        }
+    }
+
+    fn contribute(&self, result: &mut ScanResult) {
+        result.monlist = self.supports_monlist;
+        result.monlist_status = Some(self.status.clone());
+        if self.supports_monlist {
+            result.monlist_amplification = Some(self.amplification.clone());
+        }
     }
 }
@@ -0,0 +1,263 @@
+//! The mode 6 MRU list, the replacement of the mode 7 monlist in ntpd 4.2.7 and up.
+//!
+//! The client first requests a nonce (REQ_NONCE) and then pages through the list with READ_MRU.
+//! Every response carries a new nonce which has to be used for the next page,
+//! the next page is requested by sending the last received entry.
+//! The list is complete once the response contains `now=`.
+use crate::keys::Key;
+use crate::monlist::Amplification;
+use crate::packets::control;
+use crate::packets::AnyNTPPacket;
+use crate::packets::ControlReassembly;
+use crate::packets::NtpControlMessage;
+use crate::probe::Probe;
+use crate::probe::ProbeConfig;
+use crate::scan::ScanResult;
+use crate::scan::ScanState;
+use crate::scan::ScanTypeStatus;
+use crate::variables::parse_variables;
+
+/// How many entries are requested per response
+static FRAGS: u32 = 32;
+
+/// Stop paging after this many pages, as some lists are very long
+static MAX_PAGES: u32 = 64;
+
+/// A client of the server
+#[derive(Debug, Clone)]
+pub struct MruEntry {
+    /// address and port of the client
+    pub addr: String,
+    pub count: u32,
+    /// first time the client was seen (ntp timestamp)
+    pub first: u64,
+    /// last time the client was seen (ntp timestamp)
+    pub last: u64,
+    pub mode: u8,
+    pub version: u8,
+}
+
+enum Stage {
+    Nonce,
+    List,
+}
+
+pub struct MrulistProbe {
+    retries: u32,
+    versions: Vec<u8>,
+    key: Option<Key>,
+    stage: Stage,
+    sequence: u16,
+    /// the last request, resent on timeout
+    request: Vec<NtpControlMessage>,
+    reassembly: ControlReassembly,
+    nonce: Option<String>,
+    pages: u32,
+    complete: bool,
+    entries: Vec<MruEntry>,
+    amplification: Amplification,
+}
+
+impl MrulistProbe {
+    pub fn new(config: &ProbeConfig) -> Self {
+        Self {
+            retries: 0,
+            versions: config.mode6_versions.clone(),
+            key: config.control_key.clone(),
+            stage: Stage::Nonce,
+            sequence: rand::random(),
+            request: vec![],
+            reassembly: ControlReassembly::new(),
+            nonce: None,
+            pages: 0,
+            complete: false,
+            entries: vec![],
+            amplification: Amplification::default(),
+        }
+    }
+
+    /// Send a new request, every request gets a new sequence number
+    fn send_request(&mut self, state: &mut ScanState, opcode: u8, data: String) {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.reassembly = ControlReassembly::new();
+        self.retries = 0;
+        self.request = self.versions.iter().map(|&version| {
+            let mut msg = NtpControlMessage::empty();
+            msg.version = version;
+            msg.opcode = opcode;
+            msg.sequence = self.sequence;
+            msg.data = data.as_bytes().to_vec();
+            if let Some(key) = &self.key {
+                key.sign_control(&mut msg);
+            }
+            msg
+        }).collect();
+        self.queue_request(state);
+    }
+
+    fn queue_request(&mut self, state: &mut ScanState) {
+        for msg in &self.request {
+            self.amplification.request_bytes += msg.pack().len();
+            state.queue.push_back(AnyNTPPacket::Control(msg.clone()));
+        }
+    }
+
+    fn request_page(&mut self, state: &mut ScanState) {
+        let nonce = self.nonce.clone().unwrap_or_default();
+        let mut data = format!("nonce={}, frags={}", nonce, FRAGS);
+        if let Some(last) = self.entries.last() {
+            data.push_str(&format!(", last.0=0x{:08x}.{:08x}, addr.0={}", last.last >> 32, last.last & 0xffffffff, last.addr));
+        }
+        self.pages += 1;
+        self.send_request(state, control::CTL_OP_READ_MRU, data);
+    }
+
+    /// Handle a complete READ_MRU response
+    fn read_page(&mut self, state: &mut ScanState, data: &[u8]) -> ScanTypeStatus {
+        let vars = parse_variables(&String::from_utf8_lossy(data));
+        let mut page: Vec<(usize, MruEntry)> = vec![];
+        for (name, value) in &vars {
+            if name == "nonce" {
+                self.nonce = Some(value.clone());
+                continue;
+            }
+            if name == "now" {
+                self.complete = true;
+                continue;
+            }
+            let Some((field, index)) = name.split_once('.') else {
+                continue;
+            };
+            let Ok(index) = index.parse::<usize>() else {
+                continue;
+            };
+            let entry = match page.iter_mut().find(|(i, _)| *i == index) {
+                Some((_, entry)) => entry,
+                None => {
+                    page.push((index, MruEntry { addr: String::new(), count: 0, first: 0, last: 0, mode: 0, version: 0 }));
+                    &mut page.last_mut().unwrap().1
+                },
+            };
+            match field {
+                "addr" => entry.addr = value.clone(),
+                "ct" => entry.count = value.parse().unwrap_or(0),
+                "first" => entry.first = parse_lfp(value).unwrap_or(0),
+                "last" => entry.last = parse_lfp(value).unwrap_or(0),
+                "mv" => {
+                    let mv: u8 = value.parse().unwrap_or(0);
+                    entry.mode = mv & 0b111;
+                    entry.version = (mv >> 3) & 0b111;
+                },
+                _ => {},
+            }
+        }
+        page.sort_by_key(|(i, _)| *i);
+        vvprintln!("{} (mode 6) mru list page {} with {} entries", state.address, self.pages, page.len());
+        self.entries.extend(page.into_iter().map(|(_, e)| e).filter(|e| !e.addr.is_empty()));
+
+        if self.complete {
+            vprintln!("{} (mode 6) received the complete mru list ({} entries)", state.address, self.entries.len());
+            return ScanTypeStatus::Done;
+        }
+        if self.pages >= MAX_PAGES || self.entries.is_empty() || self.nonce.is_none() {
+            vprintln!("{} (mode 6) stopped reading the mru list after {} pages", state.address, self.pages);
+            return ScanTypeStatus::Done;
+        }
+        self.request_page(state);
+        ScanTypeStatus::Continue
+    }
+}
+
+/// Parse an l_fp as formatted by ntpd: `0x%08x.%08x`
+pub fn parse_lfp(str: &str) -> Option<u64> {
+    let (secs, frac) = str.strip_prefix("0x")?.split_once('.')?;
+    Some((u64::from_str_radix(secs, 16).ok()? << 32) | u64::from_str_radix(frac, 16).ok()?)
+}
+
+impl Probe for MrulistProbe {
+    fn name(&self) -> &'static str {
+        "mrulist"
+    }
+
+    fn init(&mut self, state: &mut ScanState) {
+        self.send_request(state, control::CTL_OP_REQ_NONCE, String::new());
+    }
+
+    fn accepts(&self, pkt: &AnyNTPPacket) -> bool {
+        pkt.as_control().is_some_and(|p| p.sequence == self.sequence
+            && (p.opcode == control::CTL_OP_REQ_NONCE || p.opcode == control::CTL_OP_READ_MRU))
+    }
+
+    fn receive(&mut self, state: &mut ScanState, pkt: &AnyNTPPacket) -> ScanTypeStatus {
+        let Some(pkt) = pkt.as_control() else {
+            vvprintln!("{} (mode 6) mrulist probe received non-control packet", state.address);
+            return ScanTypeStatus::Continue;
+        };
+        if pkt.sequence != self.sequence {
+            vvprintln!("{} (mode 6) mrulist probe received old sequence {}", state.address, pkt.sequence);
+            return ScanTypeStatus::Continue;
+        }
+        if !pkt.response {
+            vprintln!("{} (mode 6) received request instead of response, quitting", state.address);
+            return ScanTypeStatus::Done;
+        }
+        self.amplification.responses += 1;
+        self.amplification.response_bytes += state.last_pkt_len;
+        if pkt.error {
+            vprintln!("{} (mode 6) received error response to mru opcode {}", state.address, pkt.opcode);
+            return ScanTypeStatus::Done;
+        }
+
+        let Some(data) = self.reassembly.add(pkt) else {
+            return ScanTypeStatus::Continue;
+        };
+        match self.stage {
+            Stage::Nonce if pkt.opcode == control::CTL_OP_REQ_NONCE => {
+                let nonce = parse_variables(&String::from_utf8_lossy(&data)).into_iter().find(|(n, _)| n == "nonce");
+                match nonce {
+                    Some((_, nonce)) => {
+                        vvprintln!("{} (mode 6) received mru nonce {}", state.address, nonce);
+                        self.nonce = Some(nonce);
+                        self.stage = Stage::List;
+                        self.request_page(state);
+                        ScanTypeStatus::Continue
+                    },
+                    None => {
+                        vprintln!("{} (mode 6) nonce response without a nonce", state.address);
+                        ScanTypeStatus::Done
+                    },
+                }
+            },
+            Stage::List if pkt.opcode == control::CTL_OP_READ_MRU => self.read_page(state, &data),
+            _ => {
+                vvprintln!("{} (mode 6) mrulist probe received unexpected opcode {}", state.address, pkt.opcode);
+                ScanTypeStatus::Continue
+            },
+        }
+    }
+
+    fn timeout(&mut self, state: &mut ScanState) -> ScanTypeStatus {
+        if self.retries < state.maxretries {
+            self.retries += 1;
+            self.reassembly = ControlReassembly::new();
+            self.queue_request(state);
+            ScanTypeStatus::Continue
+        } else {
+            vprintln!("{} (mode 6) mrulist timed out", state.address);
+            ScanTypeStatus::Done
+        }
+    }
+
+    fn contribute(&self, result: &mut ScanResult) {
+        if !self.entries.is_empty() || self.complete {
+            result.mrulist = Some(self.entries.clone());
+            result.mrulist_amplification = Some(self.amplification.clone());
+        }
+    }
+}
+
+#[test]
+fn parse_mru_lfp() {
+    assert_eq!(parse_lfp("0xe8a1b2c3.80000000"), Some(0xe8a1b2c3_80000000));
+    assert_eq!(parse_lfp("e8a1b2c3.80000000"), None);
+}
@@ -0,0 +1,574 @@
+//! Network Time Security (RFC 8915).
+//!
+//! The key establishment runs over TLS 1.3 (TCP port 4460), after which an NTS protected
+//! mode 3 request is sent to the negotiated server. Both are blocking, so they run on the
+//! bounded worker threads of an [NtsPool] that the probe polls every [POLL_INTERVAL].
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::net::UdpSocket;
+use std::panic;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use aes_siv::siv::Aes128Siv;
+use aes_siv::KeyInit;
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::client::danger::ServerCertVerified;
+use rustls::client::danger::ServerCertVerifier;
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::ServerName;
+use rustls::pki_types::UnixTime;
+use rustls::DigitallySignedStruct;
+use rustls::SignatureScheme;
+
+use crate::packets::AnyNTPPacket;
+use crate::packets::ExtensionField;
+use crate::packets::NTPPacket;
+use crate::probe::Probe;
+use crate::probe::ProbeConfig;
+use crate::scan::ScanResult;
+use crate::scan::ScanState;
+use crate::scan::ScanTypeStatus;
+
+pub static NTS_KE_PORT: u16 = 4460;
+
+/// The only AEAD algorithm servers are required to support
+pub static AEAD_AES_SIV_CMAC_256: u16 = 15;
+
+/// timeout of every network operation
+static TIMEOUT: Duration = Duration::from_secs(5);
+
+/// NTS-KE record types
+pub mod record {
+    pub static END_OF_MESSAGE: u16 = 0;
+    pub static NEXT_PROTOCOL: u16 = 1;
+    pub static ERROR: u16 = 2;
+    pub static WARNING: u16 = 3;
+    pub static AEAD_ALGORITHM: u16 = 4;
+    pub static NEW_COOKIE: u16 = 5;
+    pub static SERVER: u16 = 6;
+    pub static PORT: u16 = 7;
+}
+
+/// NTS extension field types
+pub mod field {
+    pub static UNIQUE_IDENTIFIER: u16 = 0x0104;
+    pub static COOKIE: u16 = 0x0204;
+    pub static AUTHENTICATOR: u16 = 0x0404;
+}
+
+/// Pack an NTS-KE record
+pub fn pack_record(critical: bool, record_type: u16, body: &[u8]) -> Vec<u8> {
+    let mut rec = vec![];
+    rec.extend_from_slice(&(((critical as u16) << 15) | record_type).to_be_bytes());
+    rec.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    rec.extend_from_slice(body);
+    rec
+}
+
+/// Parse NTS-KE records as type and body, None if the message is not complete yet
+pub fn parse_records(mut data: &[u8]) -> Option<Vec<(u16, Vec<u8>)>> {
+    let mut records = vec![];
+    while data.len() >= 4 {
+        let record_type = u16::from_be_bytes([data[0], data[1]]) & 0x7fff;
+        let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if data.len() < 4 + len {
+            return None;
+        }
+        records.push((record_type, data[4..4 + len].to_vec()));
+        data = &data[4 + len..];
+        if record_type == record::END_OF_MESSAGE {
+            return Some(records);
+        }
+    }
+    None
+}
+
+/// Pack the value of an NTS authenticator extension field
+pub fn pack_authenticator(nonce: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+    let mut value = vec![];
+    value.extend_from_slice(&(nonce.len() as u16).to_be_bytes());
+    value.extend_from_slice(&(ciphertext.len() as u16).to_be_bytes());
+    value.extend_from_slice(nonce);
+    value.resize(value.len().next_multiple_of(4), 0);
+    value.extend_from_slice(ciphertext);
+    value.resize(value.len().next_multiple_of(4), 0);
+    value
+}
+
+/// The nonce and ciphertext of an NTS authenticator extension field
+pub fn parse_authenticator(value: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let nonce_len = u16::from_be_bytes(value.get(0..2)?.try_into().ok()?) as usize;
+    let ct_len = u16::from_be_bytes(value.get(2..4)?.try_into().ok()?) as usize;
+    let nonce = value.get(4..4 + nonce_len)?.to_vec();
+    let ct_start = 4 + nonce_len.next_multiple_of(4);
+    let ciphertext = value.get(ct_start..ct_start + ct_len)?.to_vec();
+    Some((nonce, ciphertext))
+}
+
+/// Extension fields inside the ciphertext of an authenticator
+pub fn parse_fields(mut data: &[u8]) -> Vec<ExtensionField> {
+    let mut fields = vec![];
+    while data.len() >= 4 {
+        let field_type = u16::from_be_bytes([data[0], data[1]]);
+        let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if len < 4 || len > data.len() {
+            break;
+        }
+        fields.push(ExtensionField::new(field_type, data[4..len].to_vec()));
+        data = &data[len..];
+    }
+    fields
+}
+
+/// The bytes of a packet in front of the extension field with the given index
+pub fn packet_until(pkt: &NTPPacket, index: usize) -> Vec<u8> {
+    let mut until = pkt.clone();
+    until.extensions.truncate(index);
+    until.mac = None;
+    until.pack()
+}
+
+/// Accepts every certificate but remembers if it would have been accepted,
+/// so the NTS setup of servers with broken certificates can still be audited
+#[derive(Debug)]
+struct RecordingVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    result: Mutex<Option<Result<(), String>>>,
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>, ocsp_response: &[u8], now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        let result = self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now);
+        *self.result.lock().unwrap() = Some(result.map(|_| ()).map_err(|e| e.to_string()));
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// What the NTS probe found out
+#[derive(Debug, Clone, Default)]
+pub struct NtsStatus {
+    pub aead: Option<u16>,
+    /// server and port records of the key establishment
+    pub server: Option<String>,
+    pub port: Option<u16>,
+    /// cookies received during the key establishment
+    pub cookies: usize,
+    /// None if the handshake did not get that far
+    pub certificate: Option<Result<(), String>>,
+    /// cookies received in the response to the time request
+    pub new_cookies: usize,
+    /// an NTS protected response was received and verified
+    pub authenticated: bool,
+    /// the server record points away from the target, the time request was not sent
+    pub redirected: bool,
+    /// the reason NTS did not work
+    pub error: Option<String>,
+}
+
+impl NtsStatus {
+    /// `name=value` pairs, used in the csv output
+    pub fn summary(&self) -> String {
+        let mut parts = vec![];
+        if let Some(aead) = self.aead {
+            parts.push(format!("aead={}", aead));
+        }
+        if let Some(server) = &self.server {
+            parts.push(format!("server={}", server));
+        }
+        if let Some(port) = self.port {
+            parts.push(format!("port={}", port));
+        }
+        parts.push(format!("cookies={}", self.cookies));
+        match &self.certificate {
+            Some(Ok(())) => parts.push("cert=valid".to_string()),
+            Some(Err(_)) => parts.push("cert=invalid".to_string()),
+            None => {},
+        }
+        let time = match (self.authenticated, self.redirected) {
+            (true, _) => "authenticated",
+            (false, true) => "redirected",
+            (false, false) => "failed",
+        };
+        parts.push(format!("time={}", time));
+        if let Some(error) = &self.error {
+            parts.push(format!("error=\"{}\"", error.replace([',', '"'], " ")));
+        }
+        parts.join(" ")
+    }
+}
+
+/// Keys and cookies of a successful key establishment
+struct KeyEstablishment {
+    aead: u16,
+    server: Option<String>,
+    port: Option<u16>,
+    cookies: Vec<Vec<u8>>,
+    c2s: Vec<u8>,
+    s2c: Vec<u8>,
+}
+
+fn key_establishment(addr: SocketAddr, status: &mut NtsStatus) -> anyhow::Result<KeyEstablishment> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let roots = rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    let verifier = Arc::new(RecordingVerifier {
+        inner: WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?,
+        result: Mutex::new(None),
+    });
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier.clone())
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"ntske/1".to_vec()];
+
+    let conn = rustls::ClientConnection::new(Arc::new(config), ServerName::IpAddress(addr.ip().into()))?;
+    let sock = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    sock.set_read_timeout(Some(TIMEOUT))?;
+    sock.set_write_timeout(Some(TIMEOUT))?;
+    let mut tls = rustls::StreamOwned::new(conn, sock);
+
+    let mut request = pack_record(true, record::NEXT_PROTOCOL, &0u16.to_be_bytes());
+    request.extend(pack_record(false, record::AEAD_ALGORITHM, &AEAD_AES_SIV_CMAC_256.to_be_bytes()));
+    request.extend(pack_record(true, record::END_OF_MESSAGE, &[]));
+    let written = tls.write_all(&request);
+    status.certificate = verifier.result.lock().unwrap().clone();
+    written?;
+
+    let mut response = vec![];
+    let mut buf = [0; 4096];
+    let records = loop {
+        if let Some(records) = parse_records(&response) {
+            break records;
+        }
+        let n = tls.read(&mut buf)?;
+        anyhow::ensure!(n > 0, "connection closed before the end of message record");
+        response.extend_from_slice(&buf[..n]);
+    };
+
+    let mut ke = KeyEstablishment { aead: 0, server: None, port: None, cookies: vec![], c2s: vec![], s2c: vec![] };
+    let mut next_protocol = None;
+    for (record_type, body) in records {
+        match record_type {
+            t if t == record::NEXT_PROTOCOL => next_protocol = body.get(0..2).map(|p| u16::from_be_bytes([p[0], p[1]])),
+            t if t == record::ERROR => anyhow::bail!("error record {:?}", body),
+            t if t == record::WARNING => vprintln!("{} (nts) warning record {:?}", addr, body),
+            t if t == record::AEAD_ALGORITHM && body.len() >= 2 => ke.aead = u16::from_be_bytes([body[0], body[1]]),
+            t if t == record::NEW_COOKIE => ke.cookies.push(body),
+            t if t == record::SERVER => ke.server = Some(String::from_utf8_lossy(&body).to_string()),
+            t if t == record::PORT && body.len() >= 2 => ke.port = Some(u16::from_be_bytes([body[0], body[1]])),
+            _ => {},
+        }
+    }
+    status.aead = Some(ke.aead);
+    status.server = ke.server.clone();
+    status.port = ke.port;
+    status.cookies = ke.cookies.len();
+    anyhow::ensure!(next_protocol == Some(0), "NTPv4 was not negotiated");
+    anyhow::ensure!(ke.aead == AEAD_AES_SIV_CMAC_256, "unsupported AEAD algorithm {}", ke.aead);
+    anyhow::ensure!(!ke.cookies.is_empty(), "no cookies received");
+
+    let mut context = [0, 0, (ke.aead >> 8) as u8, ke.aead as u8, 0];
+    ke.c2s = tls.conn.export_keying_material(vec![0; 32], b"EXPORTER-network-time-security", Some(&context))?;
+    context[4] = 1;
+    ke.s2c = tls.conn.export_keying_material(vec![0; 32], b"EXPORTER-network-time-security", Some(&context))?;
+    Ok(ke)
+}
+
+/// Send an NTS protected mode 3 request and verify the response
+fn time_request(addr: SocketAddr, ke: &KeyEstablishment, status: &mut NtsStatus) -> anyhow::Result<()> {
+    let uid: [u8; 32] = rand::random();
+    let nonce: [u8; 16] = rand::random();
+    let mut pkt = NTPPacket::empty();
+    pkt.version = 4;
+    pkt.mode = 3;
+    pkt.xmt = rand::random();
+    pkt.extensions = vec![
+        ExtensionField::new(field::UNIQUE_IDENTIFIER, uid.to_vec()),
+        ExtensionField::new(field::COOKIE, ke.cookies[0].clone()),
+    ];
+    let ciphertext = Aes128Siv::new_from_slice(&ke.c2s)?.encrypt([pkt.pack().as_slice(), &nonce], &[])
+        .map_err(|_| anyhow::anyhow!("failed to encrypt the request"))?;
+    pkt.extensions.push(ExtensionField::new(field::AUTHENTICATOR, pack_authenticator(&nonce, &ciphertext)));
+
+    let bind: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+    let sock = UdpSocket::bind(bind)?;
+    sock.set_read_timeout(Some(TIMEOUT))?;
+    sock.connect(addr)?;
+    sock.send(&pkt.pack())?;
+    let mut buf = [0; 2048];
+    let response = loop {
+        let n = sock.recv(&mut buf)?;
+        if let Some(AnyNTPPacket::Standard(response)) = crate::packets::parse(&buf[..n])
+            && response.org == pkt.xmt {
+            break response;
+        }
+    };
+
+    anyhow::ensure!(!(response.is_kod() && &response.refid == b"NTSN"), "NTS negative acknowledgment");
+    anyhow::ensure!(response.extensions.iter().any(|f| f.field_type == field::UNIQUE_IDENTIFIER && f.value == uid),
+        "unique identifier was not echoed");
+    let index = response.extensions.iter().position(|f| f.field_type == field::AUTHENTICATOR)
+        .ok_or_else(|| anyhow::anyhow!("response has no authenticator"))?;
+    let (nonce, ciphertext) = parse_authenticator(&response.extensions[index].value)
+        .ok_or_else(|| anyhow::anyhow!("malformed authenticator"))?;
+    let plaintext = Aes128Siv::new_from_slice(&ke.s2c)?.decrypt([packet_until(&response, index), nonce], &ciphertext)
+        .map_err(|_| anyhow::anyhow!("response failed authentication"))?;
+    status.new_cookies = parse_fields(&plaintext).iter().filter(|f| f.field_type == field::COOKIE).count();
+    status.authenticated = true;
+    Ok(())
+}
+
+/// Where to send the time request, None if the server record points to another host than the target.
+/// Those are not followed, a scanned host could direct the lookups and packets of the scan at others
+fn ntp_server(target: SocketAddr, server: Option<&str>, port: Option<u16>) -> Option<SocketAddr> {
+    let port = port.unwrap_or(123);
+    let is_target = match server {
+        None => true,
+        Some(server) => match server.parse::<IpAddr>() {
+            Ok(ip) => ip == target.ip(),
+            Err(_) => (server, port).to_socket_addrs().is_ok_and(|mut addrs| addrs.any(|a| a.ip() == target.ip())),
+        },
+    };
+    is_target.then(|| SocketAddr::new(target.ip(), port))
+}
+
+/// Run the key establishment with `addr` and a time request to the negotiated server, if that is the target
+pub fn run(addr: SocketAddr) -> NtsStatus {
+    let mut status = NtsStatus::default();
+    let result = key_establishment(addr, &mut status).and_then(|ke| {
+        let Some(ntp_addr) = ntp_server(addr, ke.server.as_deref(), ke.port) else {
+            vprintln!("{} (nts) redirected to {}, not followed", addr, ke.server.as_deref().unwrap_or_default());
+            status.redirected = true;
+            return Ok(());
+        };
+        time_request(ntp_addr, &ke, &mut status)
+    });
+    if let Err(e) = result {
+        status.error = Some(e.to_string());
+    }
+    status
+}
+
+type Job = (SocketAddr, mpsc::Sender<NtsStatus>);
+
+/// Worker threads that run the NTS exchanges of a scan, at most `workers` at the same time.
+/// The threads are started with the first exchange and stop when the pool is dropped
+#[derive(Debug)]
+pub struct NtsPool {
+    workers: usize,
+    jobs: Mutex<Option<mpsc::Sender<Job>>>,
+}
+
+impl Default for NtsPool {
+    fn default() -> Self {
+        Self::new(16)
+    }
+}
+
+impl NtsPool {
+    pub fn new(workers: usize) -> Self {
+        Self {
+            workers: workers.max(1),
+            jobs: Mutex::new(None),
+        }
+    }
+
+    /// Queue an exchange with `addr`, its status arrives on the receiver
+    pub fn submit(&self, addr: SocketAddr) -> mpsc::Receiver<NtsStatus> {
+        let (tx, rx) = mpsc::channel();
+        let mut jobs = self.jobs.lock().unwrap();
+        let jobs = jobs.get_or_insert_with(|| {
+            let (jobs, queue) = mpsc::channel::<Job>();
+            let queue = Arc::new(Mutex::new(queue));
+            for _ in 0..self.workers {
+                let queue = queue.clone();
+                thread::spawn(move || loop {
+                    let Ok((addr, tx)) = queue.lock().unwrap().recv() else {
+                        // the pool was dropped
+                        return;
+                    };
+                    let status = panic::catch_unwind(|| run(addr)).unwrap_or_else(|_| NtsStatus {
+                        error: Some("nts worker panicked".to_string()),
+                        ..Default::default()
+                    });
+                    let _ = tx.send(status);
+                });
+            }
+            jobs
+        });
+        jobs.send((addr, tx)).expect("nts workers stopped");
+        rx
+    }
+}
+
+/// How often the probe checks whether its worker is done
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub struct NtsProbe {
+    ke_port: u16,
+    pool: Arc<NtsPool>,
+    pending: Option<mpsc::Receiver<NtsStatus>>,
+    status: Option<NtsStatus>,
+}
+
+impl NtsProbe {
+    pub fn new(config: &ProbeConfig) -> Self {
+        Self {
+            ke_port: config.nts_ke_port,
+            pool: config.nts_pool.clone(),
+            pending: None,
+            status: None,
+        }
+    }
+}
+
+impl Probe for NtsProbe {
+    fn name(&self) -> &'static str {
+        "nts"
+    }
+
+    fn init(&mut self, state: &mut ScanState) {
+        let addr = SocketAddr::new(state.address.ip(), self.ke_port);
+        self.pending = Some(self.pool.submit(addr));
+    }
+
+    /// the exchange happens on its own sockets
+    fn accepts(&self, _pkt: &AnyNTPPacket) -> bool {
+        false
+    }
+
+    fn receive(&mut self, _state: &mut ScanState, _pkt: &AnyNTPPacket) -> ScanTypeStatus {
+        ScanTypeStatus::Continue
+    }
+
+    /// the exchange runs on the pool, the worker gives up on its own
+    fn timeout(&mut self, _state: &mut ScanState) -> ScanTypeStatus {
+        ScanTypeStatus::Continue
+    }
+
+    fn poll_interval(&self) -> Option<Duration> {
+        self.pending.as_ref().map(|_| POLL_INTERVAL)
+    }
+
+    fn poll(&mut self, state: &mut ScanState) -> ScanTypeStatus {
+        let Some(pending) = &self.pending else {
+            return ScanTypeStatus::Continue;
+        };
+        let status = match pending.try_recv() {
+            Ok(status) => status,
+            Err(mpsc::TryRecvError::Empty) => return ScanTypeStatus::Continue,
+            Err(mpsc::TryRecvError::Disconnected) => NtsStatus {
+                error: Some("nts worker stopped".to_string()),
+                ..Default::default()
+            },
+        };
+        self.pending = None;
+        match &status.error {
+            Some(error) => vprintln!("{} (nts) failed: {}", state.address, error),
+            None => vprintln!("{} (nts) authenticated response received", state.address),
+        }
+        self.status = Some(status);
+        ScanTypeStatus::Done
+    }
+
+    fn contribute(&self, result: &mut ScanResult) {
+        result.nts = self.status.clone();
+    }
+}
+
+#[test]
+fn nts_stand_in() {
+    // a local NTS-KE and NTP server with a self-signed certificate
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let key = rustls::pki_types::PrivateKeyDer::Pkcs8(cert.key_pair.serialize_der().into());
+    let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13]).unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.cert.der().clone()], key).unwrap();
+    config.alpn_protocols = vec![b"ntske/1".to_vec()];
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let ke_addr = listener.local_addr().unwrap();
+    let ntp_port = udp.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        let (sock, _) = listener.accept().unwrap();
+        let mut tls = rustls::StreamOwned::new(rustls::ServerConnection::new(Arc::new(config)).unwrap(), sock);
+        let mut request = vec![];
+        let mut buf = [0; 2048];
+        while parse_records(&request).is_none() {
+            let n = tls.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        let mut response = pack_record(true, record::NEXT_PROTOCOL, &[0, 0]);
+        response.extend(pack_record(true, record::AEAD_ALGORITHM, &AEAD_AES_SIV_CMAC_256.to_be_bytes()));
+        response.extend(pack_record(false, record::PORT, &ntp_port.to_be_bytes()));
+        response.extend(pack_record(false, record::NEW_COOKIE, &[1; 64]));
+        response.extend(pack_record(false, record::NEW_COOKIE, &[2; 64]));
+        response.extend(pack_record(true, record::END_OF_MESSAGE, &[]));
+        tls.write_all(&response).unwrap();
+        let label = b"EXPORTER-network-time-security";
+        let c2s = tls.conn.export_keying_material(vec![0; 32], label, Some(&[0, 0, 0, 15, 0])).unwrap();
+        let s2c = tls.conn.export_keying_material(vec![0; 32], label, Some(&[0, 0, 0, 15, 1])).unwrap();
+
+        let (n, client) = udp.recv_from(&mut buf).unwrap();
+        let request = NTPPacket::parse(&buf[..n]).unwrap();
+        let index = request.extensions.iter().position(|f| f.field_type == field::AUTHENTICATOR).unwrap();
+        let (nonce, ciphertext) = parse_authenticator(&request.extensions[index].value).unwrap();
+        Aes128Siv::new_from_slice(&c2s).unwrap().decrypt([packet_until(&request, index), nonce], &ciphertext).unwrap();
+
+        let mut response = NTPPacket::empty();
+        response.version = 4;
+        response.mode = 4;
+        response.stratum = 1;
+        response.org = request.xmt;
+        response.extensions = request.extensions.iter().filter(|f| f.field_type == field::UNIQUE_IDENTIFIER).cloned().collect();
+        let nonce: [u8; 16] = rand::random();
+        let ciphertext = Aes128Siv::new_from_slice(&s2c).unwrap()
+            .encrypt([response.pack().as_slice(), &nonce], &ExtensionField::new(field::COOKIE, vec![3; 64]).pack()).unwrap();
+        response.extensions.push(ExtensionField::new(field::AUTHENTICATOR, pack_authenticator(&nonce, &ciphertext)));
+        udp.send_to(&response.pack(), client).unwrap();
+    });
+
+    let status = NtsPool::new(1).submit(ke_addr).recv().unwrap();
+    assert_eq!(status.error, None);
+    assert!(status.authenticated);
+    assert_eq!(status.aead, Some(AEAD_AES_SIV_CMAC_256));
+    assert_eq!(status.port, Some(ntp_port));
+    assert_eq!(status.cookies, 2);
+    assert_eq!(status.new_cookies, 1);
+    // self-signed certificates are not trusted
+    assert!(matches!(status.certificate, Some(Err(_))));
+}
+
+#[test]
+fn redirects_not_followed() {
+    let target: SocketAddr = "192.0.2.1:4460".parse().unwrap();
+    assert_eq!(ntp_server(target, None, None), Some("192.0.2.1:123".parse().unwrap()));
+    assert_eq!(ntp_server(target, Some("192.0.2.1"), Some(1123)), Some("192.0.2.1:1123".parse().unwrap()));
+    assert_eq!(ntp_server(target, Some("198.51.100.7"), None), None);
+}
@@ -1,9 +1,11 @@
 use core::array::TryFromSliceError;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 use nix::sys::time::TimeSpec;
 use chrono::{Local, TimeZone};
 
-use crate::vvprintln;
 
 /// Client mode 3 packet used in [zmap](https://github.com/zmap/zmap/blob/main/examples/udp-probes/ntp_123.pkt) and nmap.
 pub static NMAP_CLIENT_MODE: &'static [u8] = &[
@@ -30,13 +32,17 @@ pub struct NTPPacket {
     pub rec: u64,
     pub xmt: u64,
     //pub dst: u8,
-    pub keyid: Option<u32>,
-    pub dgst: Option<u128>,
+    /// RFC 7822 extension fields, in between the header and the MAC
+    pub extensions: Vec<ExtensionField>,
+    /// bytes after the header that are neither valid extension fields nor a MAC
+    pub unparsed: Vec<u8>,
+    /// the MAC of a symmetric key, a crypto-NAK is a MAC without digest and keyid 0
+    pub mac: Option<Mac>,
 }
 
 impl NTPPacket {
-   pub fn pack(&self) -> [u8; 48] {
-        let mut msg = [0; 48];
+   pub fn pack(&self) -> Vec<u8> {
+        let mut msg = vec![0; 48];
         msg[0] = (self.leap << 6) | (self.version << 3) | (self.mode);
         msg[1] = self.stratum;
         msg[2] = self.poll as u8;
@@ -48,7 +54,13 @@ impl NTPPacket {
         msg[24..32].copy_from_slice(&self.org.to_be_bytes());
         msg[32..40].copy_from_slice(&self.rec.to_be_bytes());
         msg[40..48].copy_from_slice(&self.xmt.to_be_bytes());
-        if self.keyid.is_some() || self.dgst.is_some() { todo!() };
+        for field in &self.extensions {
+            msg.extend_from_slice(&field.pack());
+        }
+        if let Some(mac) = &self.mac {
+            msg.extend_from_slice(&mac.keyid.to_be_bytes());
+            msg.extend_from_slice(&mac.digest);
+        }
         msg
     }
 
@@ -67,8 +79,9 @@ impl NTPPacket {
             org: 0,
             rec: 0,
             xmt: 0,
-            keyid: None,
-            dgst: None,
+            extensions: vec![],
+            unparsed: vec![],
+            mac: None,
         }
     }
 
@@ -104,16 +117,7 @@ impl NTPPacket {
         let org = u64::from_be_bytes(data[24..32].try_into().ok()?);
         let rec = u64::from_be_bytes(data[32..40].try_into().ok()?);
         let xmt = u64::from_be_bytes(data[40..48].try_into().ok()?);
-        let keyid = if data.len() >= 52 {
-            Some(u32::from_be_bytes(data[48..52].try_into().ok()?))
-        } else {
-            None
-        };
-        let dgst = if data.len() >= 68 {
-            Some(u128::from_be_bytes(data[52..68].try_into().ok()?))
-        } else {
-            None
-        };
+        let (extensions, mac, unparsed) = parse_trailer(&data[48..]);
 
         if mode == 6 || mode == 7 {
             return None
@@ -133,8 +137,9 @@ impl NTPPacket {
             org,
             rec,
             xmt,
-            keyid,
-            dgst
+            extensions,
+            unparsed,
+            mac,
         })
     }
     
@@ -142,15 +147,82 @@ impl NTPPacket {
 
 }
 
+/// An RFC 7822 extension field
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtensionField {
+    pub field_type: u16,
+    /// the value including the padding
+    pub value: Vec<u8>,
+}
+
+impl ExtensionField {
+    pub fn new(field_type: u16, value: Vec<u8>) -> Self {
+        Self { field_type, value }
+    }
+
+    /// The value is padded to a multiple of 4 bytes, and the field to at least 16 bytes
+    pub fn pack(&self) -> Vec<u8> {
+        let len = (4 + self.value.len()).next_multiple_of(4).max(16);
+        let mut field = Vec::with_capacity(len);
+        field.extend_from_slice(&self.field_type.to_be_bytes());
+        field.extend_from_slice(&(len as u16).to_be_bytes());
+        field.extend_from_slice(&self.value);
+        field.resize(len, 0);
+        field
+    }
+}
+
+/// Split the bytes after the header into extension fields and a MAC (RFC 7822 section 7.5).
+/// A legacy MAC is 4 (crypto-NAK), 20 or 24 bytes, while an extension field
+/// is at least 16 bytes and the last one at least 28 bytes when there is no MAC,
+/// so the remaining length tells them apart.
+fn parse_trailer(mut data: &[u8]) -> (Vec<ExtensionField>, Option<Mac>, Vec<u8>) {
+    let mut extensions = vec![];
+    loop {
+        match data.len() {
+            0 => return (extensions, None, vec![]),
+            4 | 20 | 24 => {
+                let mac = Mac {
+                    keyid: u32::from_be_bytes(data[0..4].try_into().unwrap()),
+                    digest: data[4..].to_vec(),
+                };
+                return (extensions, Some(mac), vec![]);
+            },
+            n if n >= 16 => {
+                let field_type = u16::from_be_bytes([data[0], data[1]]);
+                let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+                if len < 16 || !len.is_multiple_of(4) || len > n {
+                    vvprintln!("invalid extension field of type {:#x} with length {}", field_type, len);
+                    return (extensions, None, data.to_vec());
+                }
+                extensions.push(ExtensionField::new(field_type, data[4..len].to_vec()));
+                data = &data[len..];
+            },
+            _ => return (extensions, None, data.to_vec()),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub enum AnyNTPPacket {
     Standard(NTPPacket),
     Control(NtpControlMessage),
     Private(NtpdPrivatePacket),
+    Cmdmon(CmdmonPacket),
     Invalid(Vec<u8>),
 }
 
+/// What a reply echoes from its request, to find the target of replies from unexpected addresses.
+/// Mode 7 has nothing, its sequence numbers count the fragments of a reply
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Correlation {
+    /// the xmt of a mode 3 request, the org of the reply
+    Timestamp(u64),
+    /// only 16 random bits, so the opcode and association have to match too
+    Control { sequence: u16, opcode: u8, assoc_id: u16 },
+    Cmdmon(u32),
+}
+
 pub fn parse(data: &[u8]) -> Option<AnyNTPPacket> {
     match NTPPacket::parse(data) {
         Some(pkt) => Some(AnyNTPPacket::Standard(pkt)),
@@ -163,6 +235,21 @@ pub fn parse(data: &[u8]) -> Option<AnyNTPPacket> {
 
 static EPOCH_OFFSET: u32 = 2208988800;
 
+/// The current time as an ntp timestamp
+pub fn ntp_timestamp_now() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = now.as_secs() + EPOCH_OFFSET as u64;
+    let fraction = ((now.subsec_nanos() as u64) << 32) / 10u64.pow(9);
+    (seconds << 32) | fraction
+}
+
+/// A keyid and digest trailing an authenticated packet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mac {
+    pub keyid: u32,
+    pub digest: Vec<u8>,
+}
+
 /**
 > Converting between NTP and system time can be a little messy, and is beyond the scope of this document.
  -  RFC 5905
@@ -224,8 +311,9 @@ impl fmt::Debug for NTPPacket {
                 .field("xmt", &self.xmt);
         }
         dbgstrct
-            .field("keyid", &self.keyid)
-            .field("dgst", &self.dgst)
+            .field("extensions", &self.extensions)
+            .field("unparsed", &self.unparsed)
+            .field("mac", &self.mac)
             .finish()
     }
 }
@@ -240,7 +328,11 @@ pub struct NtpControlMessage {
     pub sequence: u16,
     pub status: u16,
     pub assoc_id: u16,
+    /// offset of the data in the complete response, used for fragmented responses
+    pub offset: u16,
     pub data: Vec<u8>,
+    /// set on authenticated requests and responses, the MAC is preceded by padding to 64 bits
+    pub mac: Option<Mac>,
 }
 
 impl NtpControlMessage {
@@ -252,11 +344,15 @@ impl NtpControlMessage {
         msg.append(&mut self.sequence.to_be_bytes().to_vec());
         msg.append(&mut self.status.to_be_bytes().to_vec());
         msg.append(&mut self.assoc_id.to_be_bytes().to_vec());
-        let offset: u16 = 0;
         let count: u16 = self.data.len() as u16;
-        msg.append(&mut offset.to_be_bytes().to_vec());
+        msg.append(&mut self.offset.to_be_bytes().to_vec());
         msg.append(&mut count.to_be_bytes().to_vec());
         msg.append(&mut self.data.clone());
+        if let Some(mac) = &self.mac {
+            msg.resize(msg.len().next_multiple_of(8), 0);
+            msg.extend_from_slice(&mac.keyid.to_be_bytes());
+            msg.extend_from_slice(&mac.digest);
+        }
         msg
     }
 
@@ -310,20 +406,31 @@ impl NtpControlMessage {
         // Extract assoc_id (bytes 6-7, big-endian u16)
         let assoc_id = u16::from_be_bytes([data[6], data[7]]);
 
-        // Extract offset (bytes 8-9, big-endian u16) - we ignore this as it was hardcoded to 0
+        // Extract offset (bytes 8-9, big-endian u16), the offset of this fragment in the whole response
         let offset = u16::from_be_bytes([data[8], data[9]]);
         
         // Extract count (bytes 10-11, big-endian u16)
         let count = u16::from_be_bytes([data[10], data[11]]);
         
         // Verify we have enough data for the payload
-        if data.len() < 12 + offset as usize + count as usize {
-            vvprintln!("payload is reported to be {} bytes, but there are only {} bytes", count, (data.len() - 12 - offset as usize));
+        if data.len() < 12 + count as usize {
+            vvprintln!("payload is reported to be {} bytes, but there are only {} bytes", count, (data.len() - 12));
             return None;
         }
         
         // Extract the actual data payload
-        let payload = data[12 + offset as usize .. 12 + count as usize + offset as usize].to_vec();
+        let payload = data[12 .. 12 + count as usize].to_vec();
+
+        // a MAC follows the padding, a 4 byte keyid and at least an md5 digest
+        let padded = (12 + count as usize).next_multiple_of(8);
+        let mac = if data.len() >= padded + 4 + 16 {
+            Some(Mac {
+                keyid: u32::from_be_bytes(data[padded..padded + 4].try_into().unwrap()),
+                digest: data[padded + 4..].to_vec(),
+            })
+        } else {
+            None
+        };
         
         Some(Self {
             version,
@@ -334,7 +441,9 @@ impl NtpControlMessage {
             sequence,
             status,
             assoc_id,
+            offset,
             data: payload,
+            mac,
         })
     }
 
@@ -348,7 +457,9 @@ impl NtpControlMessage {
             sequence: 0,
             status: 0,
             assoc_id: 0,
+            offset: 0,
             data: vec![],
+            mac: None,
         }
     }
     
@@ -357,6 +468,50 @@ impl NtpControlMessage {
     }
 }
 
+pub mod control {
+    pub static CTL_OP_READSTAT: u8 = 1;
+    pub static CTL_OP_READVAR: u8 = 2;
+    pub static CTL_OP_READCLOCK: u8 = 4;
+    pub static CTL_OP_READ_MRU: u8 = 10;
+    pub static CTL_OP_REQ_NONCE: u8 = 12;
+}
+
+/// Puts the fragments of a mode 6 response back together
+#[derive(Default)]
+pub struct ControlReassembly {
+    /// fragments keyed on their offset
+    fragments: BTreeMap<u16, Vec<u8>>,
+    /// the offset at which the last fragment (the one without the more bit) ends
+    end: Option<usize>,
+}
+
+impl ControlReassembly {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a fragment, returns the complete data once every fragment has been received
+    pub fn add(&mut self, pkt: &NtpControlMessage) -> Option<Vec<u8>> {
+        if !pkt.more {
+            self.end = Some(pkt.offset as usize + pkt.data.len());
+        }
+        self.fragments.insert(pkt.offset, pkt.data.clone());
+        let end = self.end?;
+        let mut data = vec![];
+        for (&offset, fragment) in &self.fragments {
+            if offset as usize != data.len() {
+                return None;
+            }
+            data.extend_from_slice(fragment);
+        }
+        if data.len() == end {
+            Some(data)
+        } else {
+            None
+        }
+    }
+}
+
 impl AnyNTPPacket {
     pub fn as_control(&self) -> Option<&NtpControlMessage> {
         match self  {
@@ -376,11 +531,30 @@ impl AnyNTPPacket {
     pub fn is_control(&self) -> bool {
         matches!(self, AnyNTPPacket::Control(_))
     }
+    /// The correlation of a request we send
+    pub fn request_id(&self) -> Option<Correlation> {
+        match self {
+            Self::Standard(pkt) if pkt.mode == 3 && pkt.xmt != 0 => Some(Correlation::Timestamp(pkt.xmt)),
+            Self::Control(pkt) if !pkt.response => Some(Correlation::Control { sequence: pkt.sequence, opcode: pkt.opcode, assoc_id: pkt.assoc_id }),
+            Self::Cmdmon(pkt) => Some(Correlation::Cmdmon(pkt.sequence)),
+            _ => None,
+        }
+    }
+    /// The correlation of a reply, equal to the [Self::request_id] of its request
+    pub fn reply_id(&self) -> Option<Correlation> {
+        match self {
+            Self::Standard(pkt) if pkt.mode == 4 && pkt.org != 0 => Some(Correlation::Timestamp(pkt.org)),
+            Self::Control(pkt) if pkt.response => Some(Correlation::Control { sequence: pkt.sequence, opcode: pkt.opcode, assoc_id: pkt.assoc_id }),
+            Self::Cmdmon(pkt) => Some(Correlation::Cmdmon(pkt.sequence)),
+            _ => None,
+        }
+    }
     pub fn pack(&self) -> Vec<u8> {
         match self {
             Self::Standard(ntppacket) => ntppacket.pack().to_vec(),
             Self::Control(ntp_control_message) => ntp_control_message.pack().to_vec(),
             Self::Private(ntpd_private_message) => ntpd_private_message.pack().to_vec(),
+            Self::Cmdmon(cmdmon_packet) => cmdmon_packet.pack(),
             Self::Invalid(pkt) => pkt.to_vec(),
         }
     }
@@ -399,13 +573,33 @@ pub struct NtpdPrivatePacket {
     pub nitems: u16,
     pub size: u16,
     pub items: Vec<u8>,
+    /// transmit time of an authenticated request, checked by the server
+    pub tstamp: u64,
+    /// only present on authenticated requests, the responses are not signed by ntpd
+    pub mac: Option<Mac>,
 }
 
 pub mod private {
+    pub static IMPL_UNIV: u8 = 0;
+    /// size of the data field of a request, which is followed by the timestamp and MAC when authenticating
+    pub static REQ_DATA_LEN: usize = 176;
     pub static IMPL_XNTPD_OLD: u8 = 2; // used by ntpd pre ipv6 update
     pub static IMPL_XNTPD: u8 = 3; // used by ntpd post ipv6 update
+    pub static REQ_PEER_LIST: u8 = 0;
+    pub static REQ_PEER_LIST_SUM: u8 = 1;
+    pub static REQ_SYS_INFO: u8 = 4;
+    pub static REQ_SYS_STATS: u8 = 5;
+    pub static REQ_IO_STATS: u8 = 6;
+    pub static REQ_MEM_STATS: u8 = 7;
+    pub static REQ_LOOP_INFO: u8 = 8;
     pub static REQ_MON_GETLIST: u8 = 20;
     pub static REQ_MON_GETLIST_1: u8 = 42;
+    pub static REQ_IF_STATS: u8 = 44;
+    pub static INFO_ERR_IMPL: u16 = 1; // incompatible implementation
+    pub static INFO_ERR_REQ: u16 = 2; // unknown request code
+    pub static INFO_ERR_FMT: u16 = 3; // format error
+    pub static INFO_ERR_NODATA: u16 = 4; // no data for this request
+    pub static INFO_ERR_AUTH: u16 = 7; // authentication failure
 }
 
 impl NtpdPrivatePacket {
@@ -423,6 +617,8 @@ impl NtpdPrivatePacket {
             nitems: 0,
             size: 0,
             items: vec![],
+            tstamp: 0,
+            mac: None,
         }
     }
     pub fn pack(&self) -> Vec<u8> {
@@ -436,6 +632,14 @@ impl NtpdPrivatePacket {
         msg.extend_from_slice(&err_nitems.to_be_bytes());
         msg.extend_from_slice(&self.size.to_be_bytes());
         msg.extend_from_slice(&self.items);
+        if self.auth {
+            msg.resize(8 + private::REQ_DATA_LEN, 0);
+            msg.extend_from_slice(&self.tstamp.to_be_bytes());
+        }
+        if let Some(mac) = &self.mac {
+            msg.extend_from_slice(&mac.keyid.to_be_bytes());
+            msg.extend_from_slice(&mac.digest);
+        }
         msg
     }
 
@@ -483,11 +687,167 @@ impl NtpdPrivatePacket {
             nitems,
             size,
             items,
+            tstamp: 0,
+            mac: None,
         })
     }
 }
 
+/// A request or reply of chrony's command and monitoring protocol (UDP port 323)
+#[derive(Clone, Debug)]
+pub struct CmdmonPacket {
+    pub version: u8,
+    pub pkt_type: u8,
+    pub command: u16,
+    /// how often the request has been sent before
+    pub attempt: u16,
+    /// only in replies
+    pub reply: u16,
+    /// only in replies
+    pub status: u16,
+    pub sequence: u32,
+    pub data: Vec<u8>,
+    /// requests are padded to at least the size of the reply, or chronyd drops them
+    pub padded_len: usize,
+}
+
+pub mod cmdmon {
+    pub static PORT: u16 = 323;
+    pub static PROTO_VERSION: u8 = 6;
+    pub static PKT_TYPE_CMD_REQUEST: u8 = 1;
+    pub static PKT_TYPE_CMD_REPLY: u8 = 2;
+    pub static REQUEST_HEADER_LEN: usize = 20;
+    pub static REPLY_HEADER_LEN: usize = 28;
+    pub static REQ_N_SOURCES: u16 = 14;
+    pub static REQ_SOURCE_DATA: u16 = 15;
+    pub static REQ_TRACKING: u16 = 33;
+    pub static REQ_SOURCESTATS: u16 = 34;
+    pub static REQ_SERVER_STATS: u16 = 54;
+    pub static RPY_N_SOURCES: u16 = 2;
+    pub static RPY_SOURCE_DATA: u16 = 3;
+    pub static RPY_TRACKING: u16 = 5;
+    pub static RPY_SOURCESTATS: u16 = 6;
+    pub static RPY_SERVER_STATS: u16 = 14;
+    pub static RPY_SERVER_STATS2: u16 = 22;
+    pub static RPY_SERVER_STATS3: u16 = 24;
+    pub static RPY_SERVER_STATS4: u16 = 25;
+    pub static STT_SUCCESS: u16 = 0;
+    pub static STT_UNAUTH: u16 = 2;
+    pub static STT_NOHOSTACCESS: u16 = 10;
+    pub static STT_BADPKTVERSION: u16 = 18;
+}
+
+impl CmdmonPacket {
+    pub fn request(command: u16, data: Vec<u8>, padded_len: usize) -> Self {
+        Self {
+            version: cmdmon::PROTO_VERSION,
+            pkt_type: cmdmon::PKT_TYPE_CMD_REQUEST,
+            command,
+            attempt: 0,
+            reply: 0,
+            status: 0,
+            sequence: 0,
+            data,
+            padded_len,
+        }
+    }
+
+    pub fn pack(&self) -> Vec<u8> {
+        let mut msg = vec![self.version, self.pkt_type, 0, 0];
+        msg.extend_from_slice(&self.command.to_be_bytes());
+        if self.pkt_type == cmdmon::PKT_TYPE_CMD_REQUEST {
+            msg.extend_from_slice(&self.attempt.to_be_bytes());
+            msg.extend_from_slice(&self.sequence.to_be_bytes());
+            msg.extend_from_slice(&[0; 8]);
+        } else {
+            msg.extend_from_slice(&self.reply.to_be_bytes());
+            msg.extend_from_slice(&self.status.to_be_bytes());
+            msg.extend_from_slice(&[0; 6]);
+            msg.extend_from_slice(&self.sequence.to_be_bytes());
+            msg.extend_from_slice(&[0; 8]);
+        }
+        msg.extend_from_slice(&self.data);
+        if msg.len() < self.padded_len {
+            msg.resize(self.padded_len, 0);
+        }
+        msg
+    }
+
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < cmdmon::REQUEST_HEADER_LEN || data[2] != 0 || data[3] != 0 {
+            return None;
+        }
+        let pkt_type = data[1];
+        let command = u16::from_be_bytes([data[4], data[5]]);
+        if pkt_type == cmdmon::PKT_TYPE_CMD_REQUEST {
+            Some(Self {
+                version: data[0],
+                pkt_type,
+                command,
+                attempt: u16::from_be_bytes([data[6], data[7]]),
+                reply: 0,
+                status: 0,
+                sequence: u32::from_be_bytes(data[8..12].try_into().unwrap()),
+                data: data[cmdmon::REQUEST_HEADER_LEN..].to_vec(),
+                padded_len: data.len(),
+            })
+        } else if pkt_type == cmdmon::PKT_TYPE_CMD_REPLY && data.len() >= cmdmon::REPLY_HEADER_LEN {
+            Some(Self {
+                version: data[0],
+                pkt_type,
+                command,
+                attempt: 0,
+                reply: u16::from_be_bytes([data[6], data[7]]),
+                status: u16::from_be_bytes([data[8], data[9]]),
+                sequence: u32::from_be_bytes(data[16..20].try_into().unwrap()),
+                data: data[cmdmon::REPLY_HEADER_LEN..].to_vec(),
+                padded_len: 0,
+            })
+        } else {
+            None
+        }
+    }
+}
+
 #[test]
 fn parse_standard_packet() {
     parse(NMAP_CLIENT_MODE).unwrap();
 }
+
+#[test]
+fn reassemble_control_fragments() {
+    let mut first = NtpControlMessage::empty();
+    first.more = true;
+    first.data = b"srcadr=192.0.2.1, ".to_vec();
+    let mut last = NtpControlMessage::empty();
+    last.offset = first.data.len() as u16;
+    last.data = b"stratum=2".to_vec();
+
+    let parsed = NtpControlMessage::parse(&last.pack()).unwrap();
+    assert_eq!(parsed.offset, last.offset);
+
+    let mut reassembly = ControlReassembly::new();
+    assert!(reassembly.add(&parsed).is_none());
+    assert_eq!(reassembly.add(&first).unwrap(), b"srcadr=192.0.2.1, stratum=2");
+}
+
+#[test]
+fn parse_extension_fields() {
+    let mut pkt = NTPPacket::empty();
+    pkt.mode = 3;
+    pkt.extensions = vec![ExtensionField::new(0x0104, vec![1; 32]), ExtensionField::new(0x2005, vec![2; 5])];
+    pkt.mac = Some(Mac { keyid: 1, digest: vec![3; 16] });
+    let raw = pkt.pack();
+    assert_eq!(raw.len(), 48 + 36 + 16 + 20);
+    let parsed = NTPPacket::parse(&raw).unwrap();
+    assert_eq!(parsed.extensions[0], pkt.extensions[0]);
+    assert_eq!(parsed.extensions[1].value.len(), 12);
+    assert_eq!(parsed.mac, pkt.mac);
+    assert!(parsed.unparsed.is_empty());
+
+    // a field claiming to be longer than the packet
+    let mut raw = NTPPacket::empty().pack();
+    raw.extend_from_slice(&[0, 1, 0, 40]);
+    raw.extend_from_slice(&[0; 28]);
+    assert_eq!(NTPPacket::parse(&raw).unwrap().unparsed.len(), 32);
+}
@@ -0,0 +1,244 @@
+//! Enumerates the associations of a server with mode 6 READSTAT
+//! and then reads the variables of every association with READVAR,
+//! like `ntpq -c associations -c pe` does.
+use std::collections::HashMap;
+
+use crate::packets::control;
+use crate::keys::Key;
+use crate::packets::AnyNTPPacket;
+use crate::packets::ControlReassembly;
+use crate::packets::NtpControlMessage;
+use crate::probe::Probe;
+use crate::probe::ProbeConfig;
+use crate::scan::ScanResult;
+use crate::scan::ScanState;
+use crate::scan::ScanTypeStatus;
+use crate::variables::parse_variables;
+
+/// An association (usually a configured upstream) of the server
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub assoc_id: u16,
+    /// the peer status word
+    pub status: u16,
+    pub variables: Vec<(String, String)>,
+}
+
+/// The peer selection codes of the status word
+static SELECTION: [&str; 8] = ["reject", "falsetick", "excess", "outlier", "candidate", "backup", "sys.peer", "pps.peer"];
+
+impl Peer {
+    pub fn variable(&self, name: &str) -> Option<&str> {
+        self.variables.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// what the clock selection algorithm thinks of this peer
+    pub fn selection(&self) -> &'static str {
+        SELECTION[((self.status >> 8) & 0b111) as usize]
+    }
+
+    /// `srcadr/stratum/reach/offset/delay/jitter/selection`
+    pub fn summary(&self) -> String {
+        let vars = ["srcadr", "stratum", "reach", "offset", "delay", "jitter"].map(|n| self.variable(n).unwrap_or(""));
+        format!("{}/{}", vars.join("/"), self.selection())
+    }
+}
+
+enum Stage {
+    /// waiting for the association list
+    ReadStat,
+    /// waiting for the variables of the associations
+    ReadVar,
+}
+
+/// state of a READVAR request for one association
+struct ReadVarState {
+    retries: u32,
+    reassembly: ControlReassembly,
+    done: bool,
+}
+
+pub struct PeersProbe {
+    retries: u32,
+    sequence: u16,
+    versions: Vec<u8>,
+    key: Option<Key>,
+    stage: Stage,
+    statlist: ControlReassembly,
+    /// association id and status from the READSTAT response
+    associations: Vec<(u16, u16)>,
+    readvars: HashMap<u16, ReadVarState>,
+    peers: Vec<Peer>,
+}
+
+impl PeersProbe {
+    pub fn new(config: &ProbeConfig) -> Self {
+        Self {
+            retries: 0,
+            sequence: rand::random(),
+            versions: config.mode6_versions.clone(),
+            key: config.control_key.clone(),
+            stage: Stage::ReadStat,
+            statlist: ControlReassembly::new(),
+            associations: vec![],
+            readvars: HashMap::new(),
+            peers: vec![],
+        }
+    }
+
+    fn queue_request(&self, state: &mut ScanState, opcode: u8, assoc_id: u16) {
+        for &version in &self.versions {
+            let mut msg = NtpControlMessage::empty();
+            msg.version = version;
+            msg.opcode = opcode;
+            msg.sequence = self.sequence;
+            msg.assoc_id = assoc_id;
+            if let Some(key) = &self.key {
+                key.sign_control(&mut msg);
+            }
+            state.queue.push_back(AnyNTPPacket::Control(msg));
+        }
+    }
+
+    fn start_readvars(&mut self, state: &mut ScanState, data: &[u8]) -> ScanTypeStatus {
+        self.associations = data.chunks_exact(4)
+            .map(|c| (u16::from_be_bytes([c[0], c[1]]), u16::from_be_bytes([c[2], c[3]])))
+            .collect();
+        vprintln!("{} (mode 6) has {} associations", state.address, self.associations.len());
+        if self.associations.is_empty() {
+            return ScanTypeStatus::Done;
+        }
+        self.stage = Stage::ReadVar;
+        for (assoc_id, _status) in self.associations.clone() {
+            self.readvars.insert(assoc_id, ReadVarState { retries: 0, reassembly: ControlReassembly::new(), done: false });
+            self.queue_request(state, control::CTL_OP_READVAR, assoc_id);
+        }
+        ScanTypeStatus::Continue
+    }
+}
+
+impl Probe for PeersProbe {
+    fn name(&self) -> &'static str {
+        "peers"
+    }
+
+    fn init(&mut self, state: &mut ScanState) {
+        self.queue_request(state, control::CTL_OP_READSTAT, 0);
+    }
+
+    fn accepts(&self, pkt: &AnyNTPPacket) -> bool {
+        pkt.as_control().is_some_and(|p| p.sequence == self.sequence
+            && (p.opcode == control::CTL_OP_READSTAT || p.opcode == control::CTL_OP_READVAR))
+    }
+
+    fn receive(&mut self, state: &mut ScanState, pkt: &AnyNTPPacket) -> ScanTypeStatus {
+        let Some(pkt) = pkt.as_control() else {
+            vvprintln!("{} (mode 6) peers probe received non-control packet", state.address);
+            return ScanTypeStatus::Continue;
+        };
+        if !pkt.response {
+            vprintln!("{} (mode 6) received request instead of response, quitting", state.address);
+            return ScanTypeStatus::Done;
+        }
+        if pkt.error {
+            vprintln!("{} (mode 6) received error response to opcode {}", state.address, pkt.opcode);
+            if matches!(self.stage, Stage::ReadStat) {
+                return ScanTypeStatus::Done;
+            }
+        }
+
+        match self.stage {
+            Stage::ReadStat if pkt.opcode == control::CTL_OP_READSTAT => {
+                if let Some(data) = self.statlist.add(pkt) {
+                    return self.start_readvars(state, &data);
+                }
+            },
+            Stage::ReadVar if pkt.opcode == control::CTL_OP_READVAR => {
+                let Some(readvar) = self.readvars.get_mut(&pkt.assoc_id) else {
+                    vprintln!("{} (mode 6) received variables of association {} which we did not ask for", state.address, pkt.assoc_id);
+                    return ScanTypeStatus::Continue;
+                };
+                if readvar.done {
+                    return ScanTypeStatus::Continue;
+                }
+                if pkt.error {
+                    // no variables of this association, the others may still come
+                    readvar.done = true;
+                } else if let Some(data) = readvar.reassembly.add(pkt) {
+                    readvar.done = true;
+                    let status = self.associations.iter().find(|(id, _)| *id == pkt.assoc_id).map_or(0, |(_, s)| *s);
+                    let peer = Peer {
+                        assoc_id: pkt.assoc_id,
+                        status,
+                        variables: parse_variables(&String::from_utf8_lossy(&data)),
+                    };
+                    vprintln!("{} (mode 6) association {}: {}", state.address, peer.assoc_id, peer.summary());
+                    self.peers.push(peer);
+                }
+                if self.readvars.values().all(|r| r.done) {
+                    return ScanTypeStatus::Done;
+                }
+            },
+            _ => {
+                vvprintln!("{} (mode 6) peers probe received unexpected opcode {}", state.address, pkt.opcode);
+            },
+        }
+
+        ScanTypeStatus::Continue
+    }
+
+    fn timeout(&mut self, state: &mut ScanState) -> ScanTypeStatus {
+        match self.stage {
+            Stage::ReadStat => {
+                if self.retries < state.maxretries {
+                    self.retries += 1;
+                    self.statlist = ControlReassembly::new();
+                    self.queue_request(state, control::CTL_OP_READSTAT, 0);
+                    ScanTypeStatus::Continue
+                } else {
+                    vprintln!("{} (mode 6) association list timed out", state.address);
+                    ScanTypeStatus::Done
+                }
+            },
+            Stage::ReadVar => {
+                let mut retry = vec![];
+                for (assoc_id, readvar) in self.readvars.iter_mut() {
+                    if !readvar.done && readvar.retries < state.maxretries {
+                        readvar.retries += 1;
+                        readvar.reassembly = ControlReassembly::new();
+                        retry.push(*assoc_id);
+                    }
+                }
+                if retry.is_empty() {
+                    vprintln!("{} (mode 6) association variables timed out", state.address);
+                    return ScanTypeStatus::Done;
+                }
+                for assoc_id in retry {
+                    self.queue_request(state, control::CTL_OP_READVAR, assoc_id);
+                }
+                ScanTypeStatus::Continue
+            },
+        }
+    }
+
+    fn finalise(&mut self, _state: &mut ScanState) {
+        // keep the order of the association list
+        let order: Vec<u16> = self.associations.iter().map(|(id, _)| *id).collect();
+        self.peers.sort_by_key(|p| order.iter().position(|id| *id == p.assoc_id));
+    }
+
+    fn contribute(&self, result: &mut ScanResult) {
+        result.peers = self.peers.clone();
+    }
+}
+
+#[test]
+fn peer_status_word() {
+    let peer = Peer {
+        assoc_id: 1,
+        status: 0x961a,
+        variables: vec![("srcadr".to_string(), "192.0.2.1".to_string()), ("stratum".to_string(), "1".to_string())],
+    };
+    assert_eq!(peer.selection(), "sys.peer");
+    assert_eq!(peer.summary(), "192.0.2.1/1/////sys.peer");
+}
@@ -0,0 +1,163 @@
+//! Limits per network instead of per address, hosts in one /24 or /48 often share a rate limiter or firewall.
+//! The targets are interleaved by network, and optionally the targets scanned at the same time
+//! and the packets per second are limited per network.
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use crate::socket::SockAddrInet;
+
+#[derive(Debug)]
+pub struct Politeness {
+    /// prefix length of an IPv4 network
+    pub v4_prefix: u8,
+    /// prefix length of an IPv6 network
+    pub v6_prefix: u8,
+    /// targets of one network scanned at the same time
+    pub max_targets: Option<usize>,
+    /// packets per second sent to one network
+    pub rate: Option<u32>,
+    /// targets being scanned per network
+    active: Mutex<HashMap<IpAddr, usize>>,
+    /// when a network may be sent the next packet
+    next_send: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl Default for Politeness {
+    fn default() -> Self {
+        Self::new(24, 48)
+    }
+}
+
+impl Politeness {
+    pub fn new(v4_prefix: u8, v6_prefix: u8) -> Self {
+        Self {
+            v4_prefix: v4_prefix.min(32),
+            v6_prefix: v6_prefix.min(128),
+            max_targets: None,
+            rate: None,
+            active: Mutex::new(HashMap::new()),
+            next_send: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn max_targets(mut self, max: Option<usize>) -> Self {
+        self.max_targets = max.filter(|&m| m > 0);
+        self
+    }
+
+    pub fn rate(mut self, rate: Option<u32>) -> Self {
+        self.rate = rate.filter(|&r| r > 0);
+        self
+    }
+
+    /// The network of an address
+    pub fn network(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - self.v4_prefix as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(ip.to_bits() & mask))
+            },
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - self.v6_prefix as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(ip.to_bits() & mask))
+            },
+        }
+    }
+
+    /// Reorder the targets so the targets of a network are spread out,
+    /// taking one of every network in turn
+    pub fn interleave(&self, targets: Vec<SockAddrInet>) -> Vec<SockAddrInet> {
+        let mut order = vec![];
+        let mut networks: HashMap<IpAddr, VecDeque<SockAddrInet>> = HashMap::new();
+        for target in targets {
+            let network = self.network(target.ip());
+            networks.entry(network).or_insert_with(|| {
+                order.push(network);
+                VecDeque::new()
+            }).push_back(target);
+        }
+        let mut interleaved = Vec::with_capacity(networks.values().map(|n| n.len()).sum());
+        while !order.is_empty() {
+            order.retain(|network| match networks.get_mut(network).unwrap().pop_front() {
+                Some(target) => {
+                    interleaved.push(target);
+                    true
+                },
+                None => false,
+            });
+        }
+        interleaved
+    }
+
+    /// Start scanning a target, false if its network already has [Self::max_targets]
+    pub fn claim(&self, ip: IpAddr) -> bool {
+        let network = self.network(ip);
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(network).or_default();
+        if self.max_targets.is_some_and(|max| *count >= max) {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// A target claimed with [Self::claim] is done
+    pub fn release(&self, ip: IpAddr) {
+        let network = self.network(ip);
+        let mut active = self.active.lock().unwrap();
+        let Some(count) = active.get_mut(&network) else {
+            return;
+        };
+        *count -= 1;
+        if *count == 0 {
+            active.remove(&network);
+            let mut next_send = self.next_send.lock().unwrap();
+            if next_send.get(&network).is_some_and(|&at| at <= Instant::now()) {
+                next_send.remove(&network);
+            }
+        }
+    }
+
+    /// Take the turn of the network to send a packet,
+    /// or when it is its turn again if it had one too recently
+    pub fn take_turn(&self, ip: IpAddr) -> Result<(), Instant> {
+        let Some(rate) = self.rate else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let mut next_send = self.next_send.lock().unwrap();
+        let at = next_send.entry(self.network(ip)).or_insert(now);
+        if *at > now {
+            return Err(*at);
+        }
+        *at = now + Duration::from_secs(1) / rate;
+        Ok(())
+    }
+}
+
+#[test]
+fn interleave_networks() {
+    let politeness = Politeness::new(24, 48).max_targets(Some(2)).rate(Some(10));
+    let targets = ["192.0.2.1", "192.0.2.2", "192.0.2.3", "198.51.100.1", "2001:db8::1", "2001:db8:0:1::1"]
+        .iter()
+        .map(|ip| SockAddrInet::new(ip.parse().unwrap(), 123))
+        .collect();
+    let order: Vec<String> = politeness.interleave(targets).iter().map(|t| t.ip().to_string()).collect();
+    assert_eq!(order, ["192.0.2.1", "198.51.100.1", "2001:db8::1", "192.0.2.2", "2001:db8:0:1::1", "192.0.2.3"]);
+
+    let ip = "192.0.2.1".parse().unwrap();
+    assert!(politeness.claim(ip));
+    assert!(politeness.claim("192.0.2.200".parse().unwrap()));
+    assert!(!politeness.claim("192.0.2.3".parse().unwrap()));
+    politeness.release(ip);
+    assert!(politeness.claim("192.0.2.3".parse().unwrap()));
+
+    assert!(politeness.take_turn(ip).is_ok());
+    assert!(politeness.take_turn("192.0.2.9".parse().unwrap()).is_err());
+    assert!(politeness.take_turn("198.51.100.1".parse().unwrap()).is_ok());
+}
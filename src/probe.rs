@@ -0,0 +1,216 @@
+//! A probe is a single scan phase, like requesting the monlist.
+//! Each target runs the selected probes one after another,
+//! or all at once with `--parallel-probes`, see [crate::scan::ScanState].
+use crate::chrony::CmdmonProbe;
+use crate::clock::ClockProbe;
+use crate::identify::IdentifyProbe;
+use crate::keys::Key;
+use crate::mode7::Mode7Probe;
+use crate::monlist::MonlistProbe;
+use crate::mrulist::MrulistProbe;
+use crate::nts;
+use crate::nts::NtsPool;
+use crate::nts::NtsProbe;
+use crate::packets::AnyNTPPacket;
+use crate::packets::NTPPacket;
+use crate::packets::private;
+use crate::peers::PeersProbe;
+use crate::scan::ScanResult;
+use crate::scan::ScanState;
+use crate::scan::ScanTypeStatus;
+use crate::variables::VariablesProbe;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub trait Probe: Send {
+    fn name(&self) -> &'static str;
+
+    /// Whether the probe should run on the target,
+    /// probes that are not applicable are skipped without being initialized
+    fn applicable(&self, _state: &ScanState) -> bool {
+        true
+    }
+
+    /// Queue the first packets of the probe.
+    /// Note that the state's queue is not flushed here.
+    fn init(&mut self, state: &mut ScanState);
+
+    /// Whether a received packet is a response to this probe,
+    /// used to route packets when several probes are running at the same time
+    fn accepts(&self, pkt: &AnyNTPPacket) -> bool;
+
+    /// Handle a packet received while this probe was running
+    fn receive(&mut self, state: &mut ScanState, pkt: &AnyNTPPacket) -> ScanTypeStatus;
+
+    /// Called when no packet was received within the poll timeout
+    /// and the queue of the target is empty
+    fn timeout(&mut self, state: &mut ScanState) -> ScanTypeStatus;
+
+    /// How often a probe that waits for work done outside of the event loop is [polled](Self::poll),
+    /// independent of the timeout
+    fn poll_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Called every [Self::poll_interval] while the probe is running
+    fn poll(&mut self, _state: &mut ScanState) -> ScanTypeStatus {
+        ScanTypeStatus::Continue
+    }
+
+    /// Called once when the probe is done, or when the target is aborted
+    fn finalise(&mut self, _state: &mut ScanState) {}
+
+    /// Add the findings of the probe to the result
+    fn contribute(&self, result: &mut ScanResult);
+}
+
+/// Packet contents used by the probes
+#[derive(Clone, Debug)]
+pub struct ProbeConfig {
+    /// the versions the identify probe sends a mode 3 request for
+    pub identify_versions: Vec<u8>,
+    /// the mode 3 packet the identify probe sends, version and xmt are overwritten
+    pub client_template: NTPPacket,
+    /// the versions mode 6 requests are sent with
+    pub mode6_versions: Vec<u8>,
+    /// the versions mode 7 requests are sent with
+    pub mode7_versions: Vec<u8>,
+    /// key mode 6 and mode 7 requests are signed with
+    pub control_key: Option<Key>,
+    /// key the mode 3 requests of the identify probe are signed with
+    pub client_key: Option<Key>,
+    /// port of the NTS key establishment server
+    pub nts_ke_port: u16,
+    /// the threads the NTS exchanges run on, shared by every scan thread
+    pub nts_pool: Arc<NtsPool>,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        let mut client_template = NTPPacket::empty();
+        client_template.mode = 3;
+        Self {
+            identify_versions: vec![0, 1, 2, 3, 4, 5, 6, 7],
+            client_template,
+            mode6_versions: vec![3],
+            mode7_versions: vec![2],
+            control_key: None,
+            client_key: None,
+            nts_ke_port: nts::NTS_KE_PORT,
+            nts_pool: Arc::new(NtsPool::default()),
+        }
+    }
+}
+
+/// An entry in the [PROBES] registry
+pub struct ProbeInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub new: fn(&ProbeConfig) -> Box<dyn Probe>,
+}
+
+/// Every known probe
+pub static PROBES: &[ProbeInfo] = &[
+    ProbeInfo {
+        name: "variables",
+        description: "mode 6 read variables of association 0",
+        new: |config| Box::new(VariablesProbe::new(config)),
+    },
+    ProbeInfo {
+        name: "peers",
+        description: "mode 6 association list and the variables of every association",
+        new: |config| Box::new(PeersProbe::new(config)),
+    },
+    ProbeInfo {
+        name: "clockvar",
+        description: "mode 6 clock variables of the reference clock of stratum 1 servers",
+        new: |config| Box::new(ClockProbe::new(config)),
+    },
+    ProbeInfo {
+        name: "monlist",
+        description: "mode 7 monlist request",
+        new: |config| Box::new(MonlistProbe::new(config)),
+    },
+    ProbeInfo {
+        name: "peerlist",
+        description: "mode 7 peer list",
+        new: |config| Box::new(Mode7Probe::new(config, "peerlist", private::REQ_PEER_LIST)),
+    },
+    ProbeInfo {
+        name: "peersum",
+        description: "mode 7 peer summary list",
+        new: |config| Box::new(Mode7Probe::new(config, "peersum", private::REQ_PEER_LIST_SUM)),
+    },
+    ProbeInfo {
+        name: "sysinfo",
+        description: "mode 7 system info",
+        new: |config| Box::new(Mode7Probe::new(config, "sysinfo", private::REQ_SYS_INFO)),
+    },
+    ProbeInfo {
+        name: "sysstats",
+        description: "mode 7 system statistics",
+        new: |config| Box::new(Mode7Probe::new(config, "sysstats", private::REQ_SYS_STATS)),
+    },
+    ProbeInfo {
+        name: "iostats",
+        description: "mode 7 i/o statistics",
+        new: |config| Box::new(Mode7Probe::new(config, "iostats", private::REQ_IO_STATS)),
+    },
+    ProbeInfo {
+        name: "memstats",
+        description: "mode 7 peer memory statistics",
+        new: |config| Box::new(Mode7Probe::new(config, "memstats", private::REQ_MEM_STATS)),
+    },
+    ProbeInfo {
+        name: "loopinfo",
+        description: "mode 7 loop filter info",
+        new: |config| Box::new(Mode7Probe::new(config, "loopinfo", private::REQ_LOOP_INFO)),
+    },
+    ProbeInfo {
+        name: "ifstats",
+        description: "mode 7 interface list and statistics",
+        new: |config| Box::new(Mode7Probe::new(config, "ifstats", private::REQ_IF_STATS)),
+    },
+    ProbeInfo {
+        name: "mrulist",
+        description: "mode 6 mru list, the monlist of ntpd 4.2.7 and up",
+        new: |config| Box::new(MrulistProbe::new(config)),
+    },
+    ProbeInfo {
+        name: "identify",
+        description: "mode 3 requests for every ntp version",
+        new: |config| Box::new(IdentifyProbe::new(config)),
+    },
+    ProbeInfo {
+        name: "cmdmon",
+        description: "chrony's tracking, sources, sourcestats and serverstats on udp port 323",
+        new: |config| Box::new(CmdmonProbe::new(config)),
+    },
+    ProbeInfo {
+        name: "nts",
+        description: "NTS key establishment and an NTS protected time request",
+        new: |config| Box::new(NtsProbe::new(config)),
+    },
+];
+
+/// The probes that run when `--probes` is not given
+pub static DEFAULT_PROBES: &str = "variables,monlist,identify";
+
+pub fn find(name: &str) -> Option<&'static ProbeInfo> {
+    PROBES.iter().find(|p| p.name == name)
+}
+
+/// Look up a list of probe names, the error lists the available probes
+pub fn select(names: &[String]) -> anyhow::Result<Vec<&'static ProbeInfo>> {
+    names.iter().map(|name| {
+        find(name.trim()).ok_or_else(|| anyhow::anyhow!("unknown probe {}, available probes are:\n{}", name,
+            PROBES.iter().map(|p| format!("  {}: {}", p.name, p.description)).collect::<Vec<String>>().join("\n")))
+    }).collect()
+}
+
+#[test]
+fn select_probes() {
+    let probes = select(&["monlist".to_string(), "identify".to_string()]).unwrap();
+    assert_eq!(probes.iter().map(|p| p.name).collect::<Vec<_>>(), vec!["monlist", "identify"]);
+    assert!(select(&["nonexistent".to_string()]).is_err());
+}
@@ -0,0 +1,174 @@
+//! Counters of a running scan, updated by the scan threads and reported by a thread of its own.
+//! On a terminal the report is a status line that is redrawn, otherwise a log line every 10 seconds.
+use std::io::IsTerminal;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+pub struct Progress {
+    total: u64,
+    start: Instant,
+    started: AtomicU64,
+    done: AtomicU64,
+    responders: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+    kods: AtomicU64,
+    stopped: AtomicBool,
+    reporter: Mutex<Option<thread::JoinHandle<()>>>,
+    /// draw a status line instead of logging
+    tty: bool,
+    /// whether the status line is on the screen, locked while printing
+    drawn: Mutex<bool>,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Arc<Self> {
+        Arc::new(Self {
+            total: total as u64,
+            start: Instant::now(),
+            started: AtomicU64::new(0),
+            done: AtomicU64::new(0),
+            responders: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            kods: AtomicU64::new(0),
+            stopped: AtomicBool::new(false),
+            reporter: Mutex::new(None),
+            tty: std::io::stderr().is_terminal(),
+            drawn: Mutex::new(false),
+        })
+    }
+
+    pub fn started(&self) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn finished(&self, responded: bool) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+        if responded {
+            self.responders.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// A target that is not scanned, like a duplicate, it counts as done
+    pub fn skipped(&self) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn kod(&self) {
+        self.kods.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// One line with every counter, `pps` is the current rate of sent packets
+    pub fn status(&self, pps: f64) -> String {
+        let done = self.done.load(Ordering::Relaxed);
+        let elapsed = self.start.elapsed();
+        let eta = match done {
+            0 => "?".to_string(),
+            _ => {
+                let left = elapsed.mul_f64(self.total.saturating_sub(done) as f64 / done as f64);
+                format!("{}s", left.as_secs())
+            },
+        };
+        format!("{}/{} done ({:.1}%), {} in flight, {} responded, {} sent, {} received, {} kods, {:.0} pps, {}s elapsed, eta {}",
+            done, self.total,
+            done as f64 * 100.0 / self.total.max(1) as f64,
+            self.started.load(Ordering::Relaxed).saturating_sub(done),
+            self.responders.load(Ordering::Relaxed),
+            self.sent.load(Ordering::Relaxed),
+            self.received.load(Ordering::Relaxed),
+            self.kods.load(Ordering::Relaxed),
+            pps,
+            elapsed.as_secs(),
+            eta,
+        )
+    }
+
+    fn draw(&self, pps: f64) {
+        let mut drawn = self.drawn.lock().unwrap();
+        if self.tty {
+            eprint!("\r\x1b[2K{}", self.status(pps));
+            let _ = std::io::stderr().flush();
+            *drawn = true;
+        } else {
+            eprintln!("progress: {}", self.status(pps));
+        }
+    }
+
+    /// Run `f` with the status line removed, for printing results in-between
+    pub fn interrupt<R>(&self, f: impl FnOnce() -> R) -> R {
+        let mut drawn = self.drawn.lock().unwrap();
+        if *drawn {
+            eprint!("\r\x1b[2K");
+            *drawn = false;
+        }
+        f()
+    }
+
+    /// Report the progress every second on a terminal, otherwise every 10 seconds, until [Self::stop]
+    pub fn report(self: &Arc<Self>) {
+        let progress = self.clone();
+        let reporter = thread::spawn(move || {
+            let interval = if progress.tty { Duration::from_secs(1) } else { Duration::from_secs(10) };
+            let mut last = (Instant::now(), 0);
+            loop {
+                thread::sleep(Duration::from_millis(100));
+                if progress.stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                if last.0.elapsed() < interval {
+                    continue;
+                }
+                let sent = progress.sent.load(Ordering::Relaxed);
+                let pps = (sent - last.1) as f64 / last.0.elapsed().as_secs_f64();
+                last = (Instant::now(), sent);
+                progress.draw(pps);
+            }
+        });
+        *self.reporter.lock().unwrap() = Some(reporter);
+    }
+
+    /// Stop reporting and print the final counters
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(reporter) = self.reporter.lock().unwrap().take() {
+            let _ = reporter.join();
+        }
+        let pps = self.sent.load(Ordering::Relaxed) as f64 / self.start.elapsed().as_secs_f64();
+        self.interrupt(|| eprintln!("{}", self.status(pps)));
+    }
+}
+
+#[test]
+fn status_line() {
+    let mut progress = Progress::new(10);
+    Arc::get_mut(&mut progress).unwrap().start = Instant::now() - Duration::from_secs(20);
+    assert!(progress.status(0.0).ends_with("eta ?"));
+
+    for _ in 0..5 {
+        progress.started();
+    }
+    progress.finished(true);
+    progress.finished(true);
+    progress.finished(false);
+    progress.skipped();
+    progress.sent();
+    progress.received();
+    assert_eq!(progress.status(12.4), "4/10 done (40.0%), 2 in flight, 2 responded, 1 sent, 1 received, 0 kods, 12 pps, 20s elapsed, eta 30s");
+}
@@ -1,46 +1,120 @@
 use std::cell::LazyCell;
+use chrono::Local;
+use chrono::TimeZone;
 use std::fs::File;
 use std::io::Write;
 
+use crate::mode7::implementation_name;
 use crate::scan::RefId;
 use crate::scan::ScanResult;
+use crate::packets::ntp_timestamp_to_timespec;
 use crate::variables; 
 
-pub fn save_result(res: ScanResult, csv_out: &mut File, variables_out: &mut File) {
+pub fn save_result(res: &ScanResult, csv_out: &mut File, variables_out: &mut File, mrulist_out: &mut File, mode7_out: &mut File) {
     let mut versions_vec = res.versions.iter().filter_map(|(k,v)| v.map(|v| (*k,v))).collect::<Vec<(u8, u8)>>();
     versions_vec.sort_by_key(|(k,v)| *k);
     let versions_str = versions_vec.iter().map(|(k,v)| format!("{}->{}, ", k, v)).collect::<String>();
 
-    if versions_vec.is_empty() && !res.monlist && res.variables.is_none() {
+    if versions_vec.is_empty() && !res.monlist && res.variables.is_none() && res.peers.is_empty() && res.mrulist.is_none() && res.clock.is_none() && res.mode7.is_empty()
+        && !res.nts.as_ref().is_some_and(|n| n.aead.is_some()) && !res.cmdmon.as_ref().is_some_and(|c| c.responded()) {
         println!("{} offline", res.address);
     } else {
-        println!("{} refid: {:?}, versions: {}, monlist: {}, variables: {} {}",
+        let kods_str = res.kods.iter().map(|k| format!("{} ({} {}), ", k.code.name(), k.phase, k.time.format("%H:%M:%S"))).collect::<String>();
+        let peers_str = res.peers.iter().map(|p| format!("{} ({}), ", p.variable("srcadr").unwrap_or("?"), p.selection())).collect::<String>();
+        let mrulist_str = match (&res.mrulist, &res.mrulist_amplification) {
+            (Some(entries), Some(amp)) => format!("mrulist: {} entries (x{:.1}), ", entries.len(), amp.factor()),
+            _ => "".to_string(),
+        };
+        let mode7_str = res.mode7.iter().map(|a| format!("{} {} (x{:.1}), ", a.name, a.status.summary(), a.amplification.factor())).collect::<String>();
+        let monlist_status_str = match &res.monlist_status {
+            Some(status) if status.responded => format!(" {} {}", status.summary(), status.implementations_str()),
+            Some(status) => format!(" {}", status.summary()),
+            None => "".to_string(),
+        };
+        let auth_str = match &res.control_auth {
+            Some(auth) => format!("control auth: {} valid {} invalid {} unsigned, ", auth.valid, auth.invalid, auth.unsigned),
+            None => "".to_string(),
+        };
+        let client_auth_str = match &res.client_auth {
+            Some(auth) => format!("client auth: {}, ", auth.verdict()),
+            None => "".to_string(),
+        };
+        let nts_str = match &res.nts {
+            Some(nts) if nts.authenticated => format!("nts: works ({} cookies), ", nts.cookies),
+            Some(nts) if nts.redirected => format!("nts: redirected to {}, ", nts.server.as_deref().unwrap_or_default()),
+            Some(nts) if nts.aead.is_some() => format!("nts: {}, ", nts.error.as_deref().unwrap_or("failed")),
+            _ => "".to_string(),
+        };
+        let cmdmon_str = match &res.cmdmon {
+            Some(cmdmon) if cmdmon.responded() => {
+                let sources = cmdmon.sources.iter()
+                    .map(|s| format!("{} ({}), ", s.summary(), cmdmon.stats_of(s).unwrap_or_default()))
+                    .collect::<String>();
+                let tracking = cmdmon.tracking.as_ref().map_or("".to_string(), |t| format!(" tracking {},", t.summary()));
+                format!("chrony: {}{} {}", cmdmon.summary(), tracking, sources)
+            },
+            _ => "".to_string(),
+        };
+        let sources_str = match res.other_sources.as_slice() {
+            [] => "".to_string(),
+            sources => format!("replied from: {}, ", sources.iter().map(|s| s.to_string()).collect::<Vec<String>>().join(" ")),
+        };
+        let clock_str = match &res.clock {
+            Some(clock) => format!("clock: {} {}, ", clock.driver().unwrap_or("?"), clock.variable("device").unwrap_or("")),
+            None => "".to_string(),
+        };
+        println!("{} refid: {:?}, versions: {}, monlist: {}{}{}, variables: {} {}{}{}{}{}{}{}{}{}{}",
             res.address,
             res.refid,
             versions_str,
             res.monlist,
+            monlist_status_str,
+            res.monlist_amplification.as_ref().map_or("".to_string(), |amp| format!(" (x{:.1})", amp.factor())),
             res.variables.is_some(),
-            if res.rate_kod { "(rate kod)" } else { "" },
+            mrulist_str,
+            clock_str,
+            auth_str,
+            client_auth_str,
+            nts_str,
+            cmdmon_str,
+            sources_str,
+            if res.mode7.is_empty() { "".to_string() } else { format!("mode7: {}", mode7_str) },
+            if res.peers.is_empty() { "".to_string() } else { format!("peers: {}", peers_str) },
+            if res.kods.is_empty() { "".to_string() } else { format!("kods: {}", kods_str) },
         );
 
         csv_out.write(res.csv().as_bytes()).expect("error writing to csv");
 
         // save variables
-        if let Some(variables) = res.variables {
+        if let Some(variables) = &res.variables {
             variables_out.write(format!("{} {}\n", res.address, variables.trim_end()).as_bytes()).expect("error writing to variables out file");
         }
 
+        // save the clients of the mru list
+        for entry in res.mrulist.iter().flatten() {
+            mrulist_out.write_all(format!("{} {} {} {} {} {} {}\n", res.address, entry.addr, entry.count,
+                ntp_timestamp_str(entry.first), ntp_timestamp_str(entry.last), entry.mode, entry.version,
+            ).as_bytes()).expect("error writing to mrulist out file");
+        }
+
+        // save the decoded mode 7 items
+        for answer in &res.mode7 {
+            for item in &answer.items {
+                mode7_out.write_all(format!("{} {} {}\n", res.address, answer.name, item.summary()).as_bytes()).expect("error writing to mode7 out file");
+            }
+        }
+
     }
 }
 
 impl ScanResult {
     pub fn csv_header() -> &'static str {
-        "address,refid,v0,v1,v2,v3,v4,v5,v6,v7,monlist,variables\n"
+        "address,refid,v0,v1,v2,v3,v4,v5,v6,v7,monlist,monlist_amplification,monlist_status,mode7_impl,mrulist,mrulist_amplification,variables,peers,clock,mode7,control_auth,client_auth,nts,cmdmon,cmdmon_sources,other_sources,rtt,kods\n"
     }
 
     pub fn csv(&self) -> String {
         let x = self.versions.get(&0).and_then(|x| *x);
-        format!("{},{},{},{},{},{},{},{},{},{},{},{}\n",
+        format!("{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
             self.address,
             RefId::to_csv_str(&self.refid),
             self.versions.get(&0).and_then(|x| *x).map_or("".to_string(), |x| x.to_string()),
@@ -52,7 +126,41 @@ impl ScanResult {
             self.versions.get(&6).and_then(|x| *x).map_or("".to_string(), |x| x.to_string()),
             self.versions.get(&7).and_then(|x| *x).map_or("".to_string(), |x| x.to_string()),
             self.monlist.to_string(),
+            self.monlist_amplification.as_ref().map_or("".to_string(), |amp| format!("{:.1}", amp.factor())),
+            self.monlist_status.as_ref().map_or("".to_string(), |s| s.summary()),
+            self.mode7_implementations(),
+            self.mrulist.as_ref().map_or("".to_string(), |entries| entries.len().to_string()),
+            self.mrulist_amplification.as_ref().map_or("".to_string(), |amp| format!("{:.1}", amp.factor())),
             self.variables.is_some().to_string(),
+            self.peers.iter().map(|p| p.summary()).collect::<Vec<String>>().join(";"),
+            self.clock.as_ref().map_or("".to_string(), |c| c.summary().replace(',', ";")),
+            self.mode7.iter().map(|a| format!("{}:{}:{}:{:.1}", a.name, a.status.summary(), a.items.len(), a.amplification.factor())).collect::<Vec<String>>().join(";"),
+            self.control_auth.as_ref().map_or("".to_string(), |a| a.csv_str()),
+            self.client_auth.as_ref().map_or("".to_string(), |a| a.csv_str()),
+            self.nts.as_ref().map_or("".to_string(), |n| n.summary()),
+            self.cmdmon.as_ref().map_or("".to_string(), |c| c.summary()),
+            self.cmdmon.iter().flat_map(|c| c.sources.iter().map(|s| s.summary())).collect::<Vec<String>>().join(";"),
+            self.other_sources.iter().map(|s| s.to_string()).collect::<Vec<String>>().join(";"),
+            self.rtt.map_or("".to_string(), |rtt| format!("{:.1}", rtt.as_secs_f64() * 1000.0)),
+            self.kods.iter().map(|k| k.csv_str()).collect::<Vec<String>>().join(";"),
         )
     }
+
+    /// Every implementation code accepted by a mode 7 request
+    fn mode7_implementations(&self) -> String {
+        let mut implementations: Vec<u8> = self.monlist_status.iter()
+            .chain(self.mode7.iter().map(|a| &a.status))
+            .flat_map(|s| s.implementations.iter().copied())
+            .collect();
+        implementations.sort();
+        implementations.dedup();
+        implementations.into_iter().map(implementation_name).collect::<Vec<String>>().join("/")
+    }
+}
+
+/// An ntp timestamp as rfc3339
+fn ntp_timestamp_str(timestamp: u64) -> String {
+    let ts = ntp_timestamp_to_timespec(timestamp.to_be_bytes());
+    Local.timestamp_opt(ts.tv_sec(), ts.tv_nsec() as u32).single()
+        .map_or(format!("{:#x}", timestamp), |dt| dt.to_rfc3339())
 }
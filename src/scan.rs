@@ -1,52 +1,57 @@
 use std::cmp;
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::collections::VecDeque;
-use std::os::fd::AsFd;
+use std::mem;
 use std::os::fd::AsRawFd;
 use std::thread;
 use std::time::SystemTime;
 use std::time::Duration;
+use std::time::Instant;
 use std::sync::mpsc;
-use nix::poll::poll;
-use nix::poll::PollFd;
-use nix::poll::PollFlags;
+use std::sync::Arc;
+use std::sync::Mutex;
+use nix::errno::Errno;
 use nix::poll::PollTimeout;
+use nix::sys::epoll::Epoll;
+use nix::sys::epoll::EpollCreateFlags;
+use nix::sys::epoll::EpollEvent;
+use nix::sys::epoll::EpollFlags;
 use nix::sys::socket::recvfrom;
 use nix::sys::socket::AddressFamily;
 use nix::sys::socket::SockaddrIn;
 use nix::sys::socket::SockaddrIn6;
-use crate::identify;
-use crate::monlist;
-use crate::monlist::MonlistRequestStatus;
+use crate::kod::KissCode;
+use crate::kod::KodEvent;
+use crate::kod::Reaction;
 use crate::packets;
 use crate::packets::AnyNTPPacket;
+use crate::packets::Correlation;
+use crate::monlist::Amplification;
+use crate::mrulist::MruEntry;
+use crate::keys::AuthStats;
+use crate::keys::Key;
+use crate::mode7::Mode7Answer;
+use crate::mode7::Mode7Status;
+use crate::nts::NtsStatus;
+use crate::peers::Peer;
+use crate::clock::ClockVariables;
+use crate::chrony::CmdmonStatus;
+use crate::variables::parse_variables;
+use crate::probe::Probe;
+use crate::probe::ProbeConfig;
+use crate::probe::ProbeInfo;
 use crate::send;
+use crate::dispatch::Mailbox;
+use crate::politeness::Politeness;
+use crate::progress::Progress;
 use crate::socket;
 use crate::socket::SockAddrInet;
-use crate::variables;
-use crate::variables::Mode6Variables;
-use crate::variables::VersionRequestStatus;
-use crate::vprintln;
-use crate::vvprintln;
-use crate::vvvprintln;
 use crate::log::Loggable;
 
-/// What scan type is being done currently
-#[derive(Debug)]
-enum ScanType {
-    Prepare,
-    Identify,
-    Version,
-    Monlist,
-    Done,
-}
-
 /// This structure is the scan state of an address
 pub struct ScanState {
     pub address: SockAddrInet,
-    pub daemon_guess: Option<&'static str>,
-    /// key is the version sent
-    pub versions: HashMap<u8, identify::VersionState>,
     pub timeout_till: Option<SystemTime>,
     pub timeout_on_rate_kod: Duration,
     pub interval: Option<Duration>,
@@ -55,71 +60,171 @@ pub struct ScanState {
     pub queue: VecDeque<AnyNTPPacket>,
     /// a record of all received packets
     pub pkts_received: Vec<AnyNTPPacket>,
-    pub version_request_status: VersionRequestStatus,
-    pub mode6_variables: Option<Mode6Variables>,
     pub maxretries: u32,
-    pub supports_monlist: bool,
-    pub monlist_request_status: MonlistRequestStatus,
-    current_type: ScanType,
-    rate_kod_received: bool,
-    /// should the identify scan be executed
-    identify: bool,
+    /// size of the packet that is being handled
+    pub last_pkt_len: usize,
+    /// the probes which still have to run, in order
+    pending: VecDeque<Box<dyn Probe>>,
+    /// the probes that are currently running,
+    /// only one unless the probes run in parallel
+    active: Vec<Box<dyn Probe>>,
+    finished: Vec<Box<dyn Probe>>,
+    /// run all probes at the same time
+    parallel: bool,
+    /// all Kiss-o'-Death packets received
+    kods: Vec<KodEvent>,
+    /// the key mode 6 responses are verified with
+    control_key: Option<Key>,
+    auth: AuthStats,
+    /// the key mode 4 responses are verified with
+    client_key: Option<Key>,
+    client_auth: AuthStats,
+    /// how long the probes wait for a reply until the round trip time is measured
+    poll_timeout: Duration,
+    /// the lowest timeout after that
+    min_rto: Duration,
+    /// smoothed round trip time, None until a reply could be timed
+    srtt: Option<Duration>,
+    /// variation of the round trip time
+    rttvar: Duration,
+    /// timeouts of the running probe since the last measurement, each doubles the timeout
+    backoff: u32,
+    /// when the last packet was sent to or received from the target
+    last_exchange: Instant,
+    /// when the probes were last polled, see [Probe::poll_interval]
+    last_poll: Instant,
+    /// the time this state is scheduled for in the event loop, older entries are stale
+    pub(crate) wakeup: Option<Instant>,
+    /// what the sent requests can be recognized by, see [attribute]
+    sent: HashMap<Correlation, Sent>,
+    /// addresses besides the target that replies came from
+    other_sources: Vec<SockAddrInet>,
+    /// the packet rate limit of the network of the target
+    politeness: Option<Arc<Politeness>>,
+    progress: Option<Arc<Progress>>,
 }
 
+/// A request sent to the target, to time its reply
+struct Sent {
+    at: Instant,
+    /// sent more than once or already timed, its replies are no measurement (Karn's algorithm)
+    ambiguous: bool,
+}
+
+/// Upper bound of the adaptive timeout, the lower one is [ScanOptions::min_timeout]
+const MAX_RTO: Duration = Duration::from_secs(10);
+
 pub enum ScanTypeStatus {
     Continue,
     Done,
 }
 
 impl ScanState {
-    fn new(address: SockAddrInet, maxretries: u32, spread: Option<u64>, identify: bool) -> Self {
+    pub(crate) fn new(address: SockAddrInet, options: &ScanOptions) -> Self {
         ScanState {
             address,
-            daemon_guess: None,
-            versions: HashMap::new(),
             timeout_till: None,
             timeout_on_rate_kod: Duration::from_secs(10),
-            interval: spread.map(Duration::from_secs),
-            current_type: ScanType::Prepare,
+            interval: options.spread.map(Duration::from_secs),
             pkts_received: vec![],
-            maxretries,
+            maxretries: options.retries,
+            last_pkt_len: 0,
             queue: VecDeque::new(),
-            version_request_status: VersionRequestStatus::new(),
-            mode6_variables: None,
-            supports_monlist: false,
-            monlist_request_status: MonlistRequestStatus::new(),
-            rate_kod_received: false,
-            identify,
+            pending: options.probes.iter().map(|p| (p.new)(&options.probe_config)).collect(),
+            active: vec![],
+            finished: vec![],
+            parallel: options.parallel_probes,
+            kods: vec![],
+            control_key: options.probe_config.control_key.clone(),
+            auth: AuthStats::default(),
+            client_key: options.probe_config.client_key.clone(),
+            client_auth: AuthStats::default(),
+            poll_timeout: Duration::from_millis(options.polltimeout as u64),
+            min_rto: Duration::from_millis(options.min_timeout as u64),
+            srtt: None,
+            rttvar: Duration::ZERO,
+            backoff: 0,
+            last_exchange: Instant::now(),
+            last_poll: Instant::now(),
+            wakeup: None,
+            sent: HashMap::new(),
+            other_sources: vec![],
+            politeness: options.politeness.clone(),
+            progress: options.progress.clone(),
         }
     }
+    /// Initialize the next probe, or all remaining probes when running them in parallel.
     /// note that this queues the packets but does not flush them
-    fn start_next_scan(&mut self) {
+    pub(crate) fn start_probes(&mut self) {
+        while self.parallel || self.active.is_empty() {
+            let Some(mut probe) = self.pending.pop_front() else {
+                break;
+            };
+            if !probe.applicable(self) {
+                vvprintln!("{} skipping {} scan", self.address, probe.name());
+                self.finished.push(probe);
+                continue;
+            }
+            vvprintln!("{} starting {} scan", self.address, probe.name());
+            probe.init(self);
+            self.active.push(probe);
+        }
+    }
+    /// Finalise an active probe and start the next one
+    fn finish_probe(&mut self, i: usize) {
+        let mut probe = self.active.remove(i);
+        self.backoff = 0;
+        if !self.parallel {
+            self.queue.clear();
+        }
+        probe.finalise(self);
+        self.finished.push(probe);
+        self.start_probes();
+    }
+    /// Stop scanning this target, the active probes are still finalised
+    fn abort(&mut self) {
+        self.pending.clear();
         self.queue.clear();
-        match self.current_type {
-            ScanType::Prepare => {
-                self.current_type = ScanType::Version;
-                vvprintln!("{} starting mode 6 read variables scan", self.address);
-                variables::init(self);
-            },
-            ScanType::Version => {
-                self.current_type = ScanType::Monlist;
-                vvprintln!("{} starting mode 7 monlist scan", self.address);
-                monlist::init(self);
-            },
-            ScanType::Monlist => {
-                if self.identify {
-                    self.current_type = ScanType::Identify;
-                    vvprintln!("{} starting mode 3 identify scan", self.address);
-                    identify::init(self);
-                }
-                else {
-                    self.current_type = ScanType::Done;
-                }
-            },
-            ScanType::Identify => { 
-                self.current_type = ScanType::Done;
-            },
-            ScanType::Done => {},
+        while !self.active.is_empty() {
+            self.finish_probe(0);
+        }
+    }
+    /// Count the target as started in the progress
+    pub(crate) fn started(&self) {
+        if let Some(progress) = &self.progress {
+            progress.started();
+        }
+    }
+    /// Count the target as done in the progress
+    pub(crate) fn finished(&self) {
+        if let Some(progress) = &self.progress {
+            progress.finished(!self.pkts_received.is_empty());
+        }
+    }
+    pub(crate) fn is_done(&self) -> bool {
+        self.active.is_empty() && self.pending.is_empty()
+    }
+    /// The stratum of the target as far as it is known from the received packets
+    pub fn stratum(&self) -> Option<u8> {
+        let mode4 = self.pkts_received.iter()
+            .filter_map(|p| p.as_standard())
+            .find(|p| p.mode == 4 && !p.is_kod())
+            .map(|p| p.stratum);
+        mode4.or_else(|| self.pkts_received.iter()
+            .filter_map(|p| p.as_control())
+            .filter(|p| p.response && !p.error && p.opcode == packets::control::CTL_OP_READVAR && p.assoc_id == 0)
+            .filter_map(|p| p.datastr())
+            .flat_map(parse_variables)
+            .find(|(name, _)| name == "stratum")
+            .and_then(|(_, value)| value.parse().ok()))
+    }
+    /// Find the active probe a packet belongs to.
+    /// If only one probe is active it gets every packet.
+    fn route(&self, pkt: &AnyNTPPacket) -> Option<usize> {
+        match self.active.iter().position(|p| p.accepts(pkt)) {
+            Some(i) => Some(i),
+            None if self.active.len() == 1 => Some(0),
+            None => None,
         }
     }
     fn choose_sock<T: AsRawFd>(&self, sockfd4: T, sockfd6: T) -> T {
@@ -128,30 +233,165 @@ impl ScanState {
             SockAddrInet::IPv6(_) => sockfd6,
         }
     }
-    fn flush<T: AsRawFd>(&mut self, sock: T) -> nix::Result<()> {
+    /// Send the packets that may be sent now, `sent` is called with what each request can be recognized by
+    fn flush<T: AsRawFd>(&mut self, sock: T, mut sent: impl FnMut(Correlation)) -> nix::Result<()> {
         if !self.queue.is_empty() {
             vvprintln!("{} attempting to flush {} packets", self.address, self.queue.len());
         }
-        loop {
-            if self.may_send() {
-                let msg = self.queue.pop_front();
-                match msg {
-                    Some(msg) => {
-                        vvprintln!("{} sending packet", self.address);
-                        vvvprintln!("{} -> {:x?}", self.address, msg);
-                        send::send(&msg, &sock, &self.address)?;
-                        if let Some(interval) = self.interval {
-                            self.timeout_till = Some(SystemTime::now() + interval);
-                        }
-                    },
-                    None => break,
-                }
-            } else {
-                break;
+        while let Some((msg, address)) = self.next_packet() {
+            if let Some(id) = msg.request_id() {
+                sent(id);
             }
+            send::send(&msg, &sock, &address)?;
         }
         Ok(())
     }
+    /// Take the next packet from the queue if it may be sent now, and the address to send it to
+    pub(crate) fn next_packet(&mut self) -> Option<(AnyNTPPacket, SockAddrInet)> {
+        if !self.may_send() || self.queue.is_empty() {
+            return None;
+        }
+        if let Some(politeness) = &self.politeness
+            && let Err(at) = politeness.take_turn(self.address.ip()) {
+            // wait for the turn of the network
+            self.timeout_till = Some(SystemTime::now() + at.saturating_duration_since(Instant::now()));
+            return None;
+        }
+        let msg = self.queue.pop_front()?;
+        vvprintln!("{} sending packet", self.address);
+        if let Some(progress) = &self.progress {
+            progress.sent();
+        }
+        vvvprintln!("{} -> {:x?}", self.address, msg);
+        // cmdmon has its own port
+        let address = match msg {
+            AnyNTPPacket::Cmdmon(_) => self.address.with_port(packets::cmdmon::PORT),
+            _ => self.address,
+        };
+        if let Some(interval) = self.interval {
+            self.timeout_till = Some(SystemTime::now() + interval);
+        }
+        self.last_exchange = Instant::now();
+        if let Some(id) = msg.request_id() {
+            self.sent.entry(id)
+                .and_modify(|s| s.ambiguous = true)
+                .or_insert(Sent { at: Instant::now(), ambiguous: false });
+        }
+        Some((msg, address))
+    }
+    /// What the requests sent to the target can be recognized by
+    pub(crate) fn sent_ids(&self) -> impl Iterator<Item = Correlation> + '_ {
+        self.sent.keys().copied()
+    }
+    /// When the probes time out if nothing is sent or received before then
+    pub(crate) fn idle_deadline(&self) -> Instant {
+        self.last_exchange + self.rto()
+    }
+    /// The retransmission timeout, like TCP's (RFC 6298) from the measured round trip times.
+    /// The poll timeout until a reply was timed
+    pub fn rto(&self) -> Duration {
+        let rto = match self.srtt {
+            Some(srtt) => (srtt + 4 * self.rttvar).min(MAX_RTO).max(self.min_rto),
+            None => self.poll_timeout,
+        };
+        rto.saturating_mul(1 << self.backoff.min(16)).min(MAX_RTO.max(self.poll_timeout))
+    }
+    /// Update the round trip time with the reply to a request that was sent once
+    fn measure(&mut self, pkt: &AnyNTPPacket) {
+        let Some(sent) = pkt.reply_id().and_then(|id| self.sent.get_mut(&id)) else {
+            return;
+        };
+        if sent.ambiguous {
+            return;
+        }
+        // only the first fragment of a reply
+        sent.ambiguous = true;
+        let rtt = sent.at.elapsed();
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            },
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            },
+        }
+        self.backoff = 0;
+        vvprintln!("{} rtt {}ms, timeout {}ms", self.address, rtt.as_millis(), self.rto().as_millis());
+    }
+    /// When the event loop has to look at this state again:
+    /// when the queued packets may be sent, or else when the probes time out
+    pub(crate) fn next_wakeup(&self) -> Instant {
+        match self.timeout_till {
+            Some(till) if !self.queue.is_empty() => {
+                Instant::now() + till.duration_since(SystemTime::now()).unwrap_or_default()
+            },
+            _ if !self.queue.is_empty() => Instant::now(),
+            _ => self.poll_deadline().map_or(self.idle_deadline(), |poll| poll.min(self.idle_deadline())),
+        }
+    }
+    /// When the running probes that wait for work done elsewhere are polled next
+    pub(crate) fn poll_deadline(&self) -> Option<Instant> {
+        self.active.iter().filter_map(|p| p.poll_interval()).min().map(|interval| self.last_poll + interval)
+    }
+    /// Poll the running probes that have a [Probe::poll_interval]
+    pub(crate) fn poll(&mut self) {
+        self.last_poll = Instant::now();
+        let mut i = 0;
+        while i < self.active.len() {
+            if self.active[i].poll_interval().is_none() {
+                i += 1;
+                continue;
+            }
+            let mut probe = self.active.remove(i);
+            let status = probe.poll(self);
+            self.active.insert(i, probe);
+            if matches!(status, ScanTypeStatus::Done) {
+                self.finish_probe(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+    /// Handle a packet received from the target, `src` is where it actually came from
+    pub(crate) fn receive(&mut self, src: SockAddrInet, raw: &[u8], pkt: AnyNTPPacket) {
+        if let Some(progress) = &self.progress {
+            progress.received();
+        }
+        if !self.authenticate(raw, &pkt) {
+            return;
+        }
+        if src.ip() != self.address.ip() && !self.other_sources.contains(&src) {
+            vprintln!("{} replied from {src}", self.address);
+            self.other_sources.push(src);
+        }
+        self.measure(&pkt);
+        // save packet
+        self.pkts_received.push(pkt.clone());
+        self.last_pkt_len = raw.len();
+        // a response spread over many packets postpones the timeout
+        self.last_exchange = Instant::now();
+
+        let reaction = match pkt.as_standard().and_then(KissCode::from_packet) {
+            Some(code) => self.handle_kod(code, &pkt),
+            None => Reaction::Continue,
+        };
+        if matches!(reaction, Reaction::Continue | Reaction::Backoff) {
+            self.recpkt(&pkt);
+        }
+    }
+    /// Called when nothing was received for the timeout, see [Self::rto],
+    /// the probes time out once their packets have been sent
+    pub(crate) fn idle(&mut self) {
+        if self.queue.is_empty() {
+            // the retries wait longer, a probe that finishes starts over
+            self.backoff += 1;
+            self.handle_timeout();
+            // probes that keep waiting without sending are asked again after another timeout
+            self.last_exchange = Instant::now();
+        }
+    }
     fn may_send(&self) -> bool {
         if let Some(timeout) = self.timeout_till {
             timeout < SystemTime::now()
@@ -159,23 +399,79 @@ impl ScanState {
             true
         }
     }
-    fn recpkt(&mut self, pkt: &AnyNTPPacket) -> ScanTypeStatus {
-        match self.current_type {
-            ScanType::Prepare => unreachable!(),
-            ScanType::Identify => identify::receive(self, pkt),
-            ScanType::Version => variables::receive(self, pkt),
-            ScanType::Monlist => monlist::receive(self, pkt),
-            ScanType::Done => ScanTypeStatus::Done,
+    fn recpkt(&mut self, pkt: &AnyNTPPacket) {
+        let Some(i) = self.route(pkt) else {
+            vprintln!("{} received a packet that none of the running probes expected", self.address);
+            return;
+        };
+        let mut probe = self.active.remove(i);
+        let status = probe.receive(self, pkt);
+        self.active.insert(i, probe);
+        if matches!(status, ScanTypeStatus::Done) {
+            self.finish_probe(i);
+        }
+    }
+    /// Verify the MAC of a mode 4 or mode 6 response when the requests are signed,
+    /// returns false if the packet has to be dropped
+    fn authenticate(&mut self, raw: &[u8], pkt: &AnyNTPPacket) -> bool {
+        let valid = match pkt {
+            AnyNTPPacket::Standard(pkt) => match &self.client_key {
+                Some(key) => self.client_auth.check(key, raw, pkt.mac.as_ref()),
+                None => true,
+            },
+            AnyNTPPacket::Control(msg) => match &self.control_key {
+                Some(key) => self.auth.check(key, raw, msg.mac.as_ref()),
+                None => true,
+            },
+            _ => true,
+        };
+        if !valid {
+            vprintln!("{} response has an invalid MAC, dropping it", self.address);
+        }
+        valid
+    }
+    fn handle_timeout(&mut self) {
+        let mut i = 0;
+        while i < self.active.len() {
+            let mut probe = self.active.remove(i);
+            let status = probe.timeout(self);
+            self.active.insert(i, probe);
+            if matches!(status, ScanTypeStatus::Done) {
+                self.finish_probe(i);
+            } else {
+                i += 1;
+            }
         }
     }
-    fn handle_timeout(&mut self) -> ScanTypeStatus {
-        match self.current_type {
-            ScanType::Prepare => unreachable!(),
-            ScanType::Identify => identify::timeout(self),
-            ScanType::Version => variables::timeout(self),
-            ScanType::Monlist => monlist::timeout(self),
-            ScanType::Done => unreachable!(),
+    /// Record a KoD and react to it as described by [KissCode::reaction]
+    fn handle_kod(&mut self, code: KissCode, pkt: &AnyNTPPacket) -> Reaction {
+        let probe = self.route(pkt);
+        let phase = probe.map_or("done", |i| self.active[i].name());
+        self.kods.push(KodEvent::new(code, phase));
+        if let Some(progress) = &self.progress {
+            progress.kod();
         }
+        let reaction = code.reaction();
+        match reaction {
+            Reaction::Continue => {
+                vprintln!("{} Kiss o' Death {} received", self.address, code.name());
+            },
+            Reaction::Backoff => {
+                vprintln!("{} Kiss o' Death {} received, backing off", self.address, code.name());
+                self.handle_rate_kod();
+            },
+            Reaction::SkipPhase => {
+                vprintln!("{} Kiss o' Death {} received, skipping {} scan", self.address, code.name(), phase);
+                if let Some(i) = probe {
+                    self.finish_probe(i);
+                }
+            },
+            Reaction::Abort => {
+                eprintln!("{} Kiss o' Death {} received, quitting", self.address, code.name());
+                self.abort();
+            },
+        }
+        reaction
     }
     fn handle_rate_kod(&mut self) {
         self.timeout_till = Some(SystemTime::now() + self.timeout_on_rate_kod);
@@ -192,15 +488,21 @@ impl ScanState {
 
         // an upper limit on how long we are willing to wait
         if self.timeout_on_rate_kod >= Duration::from_secs(120) {
-            self.current_type = ScanType::Done;
+            self.abort();
         }
     }
 
-    fn to_result(&self) -> ScanResult {
+    pub(crate) fn to_result(&self) -> ScanResult {
         let mode4pkt = self.pkts_received
             .iter()
             .filter_map(|p| p.as_standard())
-            .find(|pk| pk.mode == 4 && pk.refidstr().unwrap_or("") != "RATE");
+            .filter(|pk| pk.mode == 4)
+            .min_by_key(|pk| match KissCode::from_packet(pk) {
+                None => 0,
+                Some(code) if code.reaction() == Reaction::Continue => 1,
+                Some(_) => 2,
+            })
+            .filter(|pk| KissCode::from_packet(pk).is_none_or(|code| code.reaction() == Reaction::Continue));
         let refid = match mode4pkt {
             Some(p) => match p.refidstr() {
                 Some(str) => Some(RefId::Ascii(str.to_string())),
@@ -208,16 +510,34 @@ impl ScanState {
             },
             None => None,
         };
-        let versions = self.versions.clone().iter().map(|(vi, vs)| (*vi, vs.response.as_ref().map(|p| p.version))).collect();
-        ScanResult {
+        let stratum = mode4pkt.map(|p| p.stratum);
+        let mut result = ScanResult {
             address: self.address,
-            daemon_guess: self.daemon_guess.unwrap_or(""),
+            daemon_guess: "",
             refid: refid,
-            versions,
-            monlist: self.supports_monlist,
-            variables: self.mode6_variables.as_ref().map(|v| v.str.clone()),
-            rate_kod: self.rate_kod_received,
+            stratum,
+            versions: HashMap::new(),
+            monlist: false,
+            monlist_amplification: None,
+            monlist_status: None,
+            mrulist: None,
+            mrulist_amplification: None,
+            variables: None,
+            peers: vec![],
+            clock: None,
+            mode7: vec![],
+            kods: self.kods.clone(),
+            control_auth: self.control_key.as_ref().map(|_| self.auth.clone()),
+            client_auth: self.client_key.as_ref().map(|_| self.client_auth.clone()),
+            nts: None,
+            cmdmon: None,
+            other_sources: self.other_sources.clone(),
+            rtt: self.srtt,
+        };
+        for probe in &self.finished {
+            probe.contribute(&mut result);
         }
+        result
     }
 
 }
@@ -229,6 +549,19 @@ pub enum RefId {
 }
 
 impl RefId {
+    /// the raw four bytes of the refid
+    pub fn bytes(&self) -> [u8; 4] {
+        match self {
+            RefId::Ascii(ascii_str) => {
+                let mut bytes = [0; 4];
+                let len = ascii_str.len().min(4);
+                bytes[..len].copy_from_slice(&ascii_str.as_bytes()[..len]);
+                bytes
+            },
+            RefId::Other(bytes) => *bytes,
+        }
+    }
+
     pub fn to_csv_str(refid: &Option<RefId>) -> String {
         match refid {
             Some(RefId::Ascii(ascii_str)) => {
@@ -259,146 +592,566 @@ pub struct ScanResult {
     pub address: SockAddrInet,
     pub daemon_guess: &'static str,
     pub refid: Option<RefId>,
+    pub stratum: Option<u8>,
     pub versions: HashMap<u8, Option<u8>>,
     pub monlist: bool,
+    pub monlist_amplification: Option<Amplification>,
+    /// None if the monlist probe did not run
+    pub monlist_status: Option<Mode7Status>,
+    /// None if the mru list could not be read
+    pub mrulist: Option<Vec<MruEntry>>,
+    pub mrulist_amplification: Option<Amplification>,
     pub variables: Option<String>,
-    pub rate_kod: bool,
+    pub peers: Vec<Peer>,
+    pub clock: Option<ClockVariables>,
+    /// the answered mode 7 requests besides monlist
+    pub mode7: Vec<Mode7Answer>,
+    pub kods: Vec<KodEvent>,
+    /// how the mode 6 responses were signed, if the requests were
+    pub control_auth: Option<AuthStats>,
+    /// how the mode 4 responses were signed, if the requests were
+    pub client_auth: Option<AuthStats>,
+    /// None if the nts probe did not run
+    pub nts: Option<NtsStatus>,
+    /// None if the cmdmon probe did not run
+    pub cmdmon: Option<CmdmonStatus>,
+    /// addresses besides the target that replies came from
+    pub other_sources: Vec<SockAddrInet>,
+    /// smoothed round trip time, None if no reply could be timed
+    pub rtt: Option<Duration>,
+}
+
+/// Settings shared by every target of a scan thread
+#[derive(Clone)]
+pub struct ScanOptions {
+    /// how often to retry sending a packet
+    pub retries: u32,
+    /// how many targets a thread scans at the same time
+    pub concurrent: usize,
+    /// how long to wait for a reply (in ms) until the round trip time of a target is measured
+    pub polltimeout: u32,
+    /// the lowest timeout (in ms) adapted to the round trip time, a retransmission before
+    /// a slow reply arrives would be no measurement
+    pub min_timeout: u32,
+    /// interval in-between sent packets in secs
+    pub spread: Option<u64>,
+    /// a fixed source port shared by the threads, see [crate::dispatch]
+    pub source_port: Option<u16>,
+    /// limits per network shared by the threads
+    pub politeness: Option<Arc<Politeness>>,
+    /// counters of the scan, see [crate::progress]
+    pub progress: Option<Arc<Progress>>,
+    /// the probes to run on every target
+    pub probes: Vec<&'static ProbeInfo>,
+    /// run the probes of a target at the same time instead of one after another
+    pub parallel_probes: bool,
+    pub probe_config: ProbeConfig,
+}
+
+/// The targets that sent a request, by what its replies echo, see [attribute]
+#[derive(Debug, Default)]
+pub(crate) struct Correlations(HashMap<Correlation, Vec<SockAddrInet>>);
+
+impl Correlations {
+    pub(crate) fn insert(&mut self, id: Correlation, target: SockAddrInet) {
+        let targets = self.0.entry(id).or_default();
+        if !targets.contains(&target) {
+            targets.push(target);
+        }
+    }
+
+    /// Forget the requests of a finished target
+    pub(crate) fn forget(&mut self, target: SockAddrInet, ids: impl IntoIterator<Item = Correlation>) {
+        for id in ids {
+            if let Some(targets) = self.0.get_mut(&id) {
+                targets.retain(|&t| t != target);
+                if targets.is_empty() {
+                    self.0.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// The target that sent the request, None if no or more than one target did
+    pub(crate) fn find(&self, id: &Correlation) -> Option<SockAddrInet> {
+        match self.0.get(id)?.as_slice() {
+            [target] => Some(*target),
+            _ => None,
+        }
+    }
 }
 
-pub fn start_thread(targets: Vec<SockAddrInet>, retries: u32, concurrent: usize, polltimeout: u32, spread: Option<u64>, identify: bool) -> mpsc::Receiver<ScanResult> {
-    let (tx, rx) = mpsc::channel();
+/// Parse a packet received from `src` and find the target it belongs to.
+/// Replies from an address that is not a target, like another address of a multi-homed server,
+/// are matched to the target that sent the request they echo, as long as only one target sent it.
+/// The target may be of another thread when it shares the source port
+pub(crate) fn attribute(states: &HashMap<SockAddrInet, ScanState>, lookup: impl Fn(&Correlation) -> Option<SockAddrInet>,
+    src: SockAddrInet, raw: &[u8]) -> Option<(SockAddrInet, AnyNTPPacket)> {
+    let pkt_option = if src.port() == packets::cmdmon::PORT && !states.contains_key(&src) {
+        packets::CmdmonPacket::parse(raw).map(AnyNTPPacket::Cmdmon)
+    } else {
+        packets::parse(raw)
+    };
+    let Some(pkt) = pkt_option else {
+        vprintln!("failed to parse {} byte pkt from {src}", raw.len());
+        return None;
+    };
+    vvprintln!("{} bytes from {src}", raw.len());
+    vvprintln!("{pkt:?}");
+
+    // cmdmon replies come from another port than the target
+    let src = match pkt {
+        AnyNTPPacket::Cmdmon(_) => states.keys().find(|a| a.ip() == src.ip()).copied().unwrap_or(src),
+        _ => src,
+    };
+    if !states.contains_key(&src)
+        && let Some(id) = pkt.reply_id()
+        && let Some(target) = lookup(&id) {
+        vvprintln!("{src} answered a request sent to {target}");
+        return Some((target, pkt));
+    }
+    Some((src, pkt))
+}
+
+/// The targets of a scan shared by its threads,
+/// a thread takes the next one whenever one of its concurrent slots frees up
+pub struct TargetQueue {
+    targets: Mutex<VecDeque<SockAddrInet>>,
+    len: usize,
+    politeness: Option<Arc<Politeness>>,
+}
+
+/// How far [TargetQueue::next] looks for a target of a network that is not at its limit
+const LOOKAHEAD: usize = 1024;
+/// How often a thread whose free slots only have targets of networks at their limit tries again
+pub(crate) const BLOCKED_RETRY: Duration = Duration::from_millis(50);
+
+impl TargetQueue {
+    /// The targets are taken in order, the politeness limits the targets per network
+    pub fn new(targets: Vec<SockAddrInet>, politeness: Option<Arc<Politeness>>) -> Self {
+        Self { len: targets.len(), targets: Mutex::new(targets.into()), politeness }
+    }
+
+    /// Take the next target whose network may be scanned,
+    /// None if the queue is empty or every network ahead is at its limit, see [Self::is_empty]
+    pub fn next(&self) -> Option<SockAddrInet> {
+        let mut targets = self.targets.lock().unwrap();
+        let Some(politeness) = &self.politeness else {
+            return targets.pop_front();
+        };
+        let i = targets.iter().take(LOOKAHEAD).position(|t| politeness.claim(t.ip()))?;
+        targets.remove(i)
+    }
+
+    /// A target taken with [Self::next] is done
+    pub fn done(&self, address: SockAddrInet) {
+        if let Some(politeness) = &self.politeness {
+            politeness.release(address.ip());
+        }
+    }
+
+    /// The amount of targets of the scan
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether every target was taken
+    pub fn is_empty(&self) -> bool {
+        self.targets.lock().unwrap().is_empty()
+    }
+}
+
+/// Scan targets from the queue in a new thread until it is empty, the results are sent to `tx`.
+/// The mailbox is required when the threads share a source port
+pub fn start_thread(targets: Arc<TargetQueue>, options: ScanOptions, tx: mpsc::Sender<ScanResult>, mailbox: Option<Mailbox>) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-        scan_thread(tx, &targets, retries, concurrent, polltimeout, spread, identify);
-    });
-    rx
+        scan_thread(tx, &targets, &options, mailbox.as_ref());
+    })
+}
+
+/// A target to look at once `when` has passed, stale entries are skipped, see [ScanState::wakeup]
+struct Deadline {
+    when: Instant,
+    address: SockAddrInet,
+}
+
+// ordered by time only, the earliest deadline is the greatest for the max-heap
+impl Ord for Deadline {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        other.when.cmp(&self.when)
+    }
+}
+
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Deadline {
+    fn eq(&self, other: &Self) -> bool {
+        self.when == other.when
+    }
+}
+
+impl Eq for Deadline {}
+
+/// The epoll timeout until `when`, rounded up to whole milliseconds,
+/// otherwise a deadline less than a millisecond away returns immediately until it passes
+fn poll_timeout(when: Instant) -> PollTimeout {
+    let ms = when.saturating_duration_since(Instant::now()).as_micros().div_ceil(1000);
+    PollTimeout::try_from(Duration::from_millis(ms.try_into().unwrap_or(u64::MAX))).unwrap_or(PollTimeout::MAX)
+}
+
+/// Schedule the next wakeup of a state, unless it is already scheduled for that time
+fn schedule(deadlines: &mut BinaryHeap<Deadline>, state: &mut ScanState) {
+    let wakeup = state.next_wakeup();
+    if state.wakeup != Some(wakeup) {
+        state.wakeup = Some(wakeup);
+        deadlines.push(Deadline { when: wakeup, address: state.address });
+    }
+}
+
+/// The targets a thread is scanning
+struct Scanning<'a> {
+    states: HashMap<SockAddrInet, ScanState>,
+    deadlines: BinaryHeap<Deadline>,
+    /// the requests sent by the states, in the dispatcher instead when there is a mailbox
+    index: Correlations,
+    mailbox: Option<&'a Mailbox>,
+    /// the targets that finished since they were last collected
+    done: Vec<SockAddrInet>,
+}
+
+/// Send what a state has queued and register its requests
+fn flush<T: AsRawFd + Copy>(state: &mut ScanState, index: &mut Correlations, mailbox: Option<&Mailbox>, sockfd4: T, sockfd6: T) {
+    let address = state.address;
+    state.flush(state.choose_sock(sockfd4, sockfd6), |id| match mailbox {
+        Some(mailbox) => mailbox.register(id, address),
+        None => index.insert(id, address),
+    }).expect("error flushing");
 }
 
-fn scan_thread(tx: mpsc::Sender<ScanResult>, targets: &[SockAddrInet], maxretries: u32, concurrent: usize, polltimeout: u32, spread: Option<u64>, identify: bool) {
-    let mut i = concurrent.min(targets.len());
-    let mut states: HashMap<SockAddrInet, ScanState> = HashMap::new();
-    for state in targets[0..i].iter().map(|a| ScanState::new(*a, maxretries, spread, identify)) {
-        if states.contains_key(&state.address) {
-            println!("duplicate address {}", state.address);
+impl<'a> Scanning<'a> {
+    fn new(mailbox: Option<&'a Mailbox>) -> Self {
+        Self {
+            states: HashMap::new(),
+            deadlines: BinaryHeap::new(),
+            index: Correlations::default(),
+            mailbox,
+            done: vec![],
+        }
+    }
+
+    /// Take the next target from the queue and send its first packets, false if there is none to take
+    fn add_target<T: AsRawFd + Copy>(&mut self, targets: &TargetQueue, options: &ScanOptions, sockfd4: T, sockfd6: T) -> bool {
+        let address = loop {
+            let Some(address) = targets.next() else {
+                return false;
+            };
+            if !self.states.contains_key(&address) {
+                break address;
+            }
+            eprintln!("duplicate address {}", address);
+            targets.done(address);
+            if let Some(progress) = &options.progress {
+                progress.skipped();
+            }
+        };
+        let mut state = ScanState::new(address, options);
+        vvprintln!("added {} to concurrent targets", state.address);
+        state.started();
+        if let Some(mailbox) = self.mailbox {
+            // before sending, the reply may arrive at another thread
+            mailbox.claim(address.ip());
+        }
+        state.start_probes();
+        flush(&mut state, &mut self.index, self.mailbox, sockfd4, sockfd6);
+        schedule(&mut self.deadlines, &mut state);
+        self.states.insert(address, state);
+        true
+    }
+
+    /// Pass a received packet to its target and send what it answers with.
+    /// The target if it is none of this thread's
+    fn handle_packet<T: AsRawFd + Copy>(&mut self, src: SockAddrInet, raw: &[u8], sockfd4: T, sockfd6: T) -> Result<(), SockAddrInet> {
+        let (index, mailbox) = (&self.index, self.mailbox);
+        let lookup = |id: &Correlation| match mailbox {
+            Some(mailbox) => mailbox.find(id),
+            None => index.find(id),
+        };
+        let Some((target, pkt)) = attribute(&self.states, lookup, src, raw) else {
+            // unparseable, nothing to forward
+            return Ok(());
+        };
+        let Some(state) = self.states.get_mut(&target) else {
+            return Err(target);
+        };
+        state.receive(src, raw, pkt);
+        flush(state, &mut self.index, self.mailbox, sockfd4, sockfd6);
+        if state.is_done() {
+            self.done.push(state.address);
         } else {
-            states.insert(state.address, state);
+            schedule(&mut self.deadlines, state);
+        }
+        Ok(())
+    }
+
+    /// Time out and flush the states whose deadline passed
+    fn wake_due<T: AsRawFd + Copy>(&mut self, sockfd4: T, sockfd6: T) {
+        let now = Instant::now();
+        while self.deadlines.peek().is_some_and(|d| d.when <= now) {
+            let Deadline { when, address } = self.deadlines.pop().unwrap();
+            let Some(state) = self.states.get_mut(&address) else {
+                continue;
+            };
+            if state.wakeup != Some(when) {
+                // rescheduled since
+                continue;
+            }
+            state.wakeup = None;
+            if state.queue.is_empty() && now >= state.idle_deadline() {
+                vvprintln!("{} timeout", state.address);
+                state.idle();
+            }
+            if state.poll_deadline().is_some_and(|poll| now >= poll) {
+                state.poll();
+            }
+            flush(state, &mut self.index, self.mailbox, sockfd4, sockfd6);
+            if state.is_done() {
+                self.done.push(state.address);
+            } else {
+                schedule(&mut self.deadlines, state);
+            }
         }
     }
 
-    let sockfd4 = socket::setup_socket(AddressFamily::Inet).expect("Failed to bind IPv4 UDP socket");
-    let sockfd6 = socket::setup_socket(AddressFamily::Inet6).expect("Failed to bind IPv6 UDP socket");
+    /// Remove a finished target
+    fn remove(&mut self, address: SockAddrInet) -> Option<ScanState> {
+        let state = self.states.remove(&address)?;
+        match self.mailbox {
+            Some(mailbox) => mailbox.release(address, state.sent_ids()),
+            None => self.index.forget(address, state.sent_ids()),
+        }
+        Some(state)
+    }
+}
 
-    // initialize the first scan for all targets
-    for (_, state) in states.iter_mut() {
-        state.start_next_scan();
-        state.flush(state.choose_sock(sockfd4.as_raw_fd(), sockfd6.as_raw_fd())).expect("error flushing");
+/// The event loop of a scan thread.
+/// Every target has its own deadline in a heap, so a target's probes time out
+/// exactly its [ScanState::rto] after its last exchange and only due targets are visited.
+fn scan_thread(tx: mpsc::Sender<ScanResult>, targets: &TargetQueue, options: &ScanOptions, mailbox: Option<&Mailbox>) {
+    let (sockfd4, sockfd6) = match options.source_port {
+        Some(port) => (
+            socket::setup_shared_socket(AddressFamily::Inet, port).expect("Failed to bind IPv4 UDP socket"),
+            socket::setup_shared_socket(AddressFamily::Inet6, port).expect("Failed to bind IPv6 UDP socket"),
+        ),
+        None => (
+            socket::setup_socket(AddressFamily::Inet).expect("Failed to bind IPv4 UDP socket"),
+            socket::setup_socket(AddressFamily::Inet6).expect("Failed to bind IPv6 UDP socket"),
+        ),
+    };
+    let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC).expect("Failed to create epoll instance");
+    epoll.add(&sockfd4, EpollEvent::new(EpollFlags::EPOLLIN, 4)).expect("epoll_ctl(2) failed");
+    epoll.add(&sockfd6, EpollEvent::new(EpollFlags::EPOLLIN, 6)).expect("epoll_ctl(2) failed");
+    if let Some(mailbox) = mailbox {
+        epoll.add(mailbox, EpollEvent::new(EpollFlags::EPOLLIN, 0)).expect("epoll_ctl(2) failed");
     }
+    let (fd4, fd6) = (sockfd4.as_raw_fd(), sockfd6.as_raw_fd());
 
-    let mut pollfds = [
-        PollFd::new(sockfd4.as_fd(), PollFlags::POLLIN),
-        PollFd::new(sockfd6.as_fd(), PollFlags::POLLIN),
-    ];
-    let poll_timeout = PollTimeout::from(polltimeout as u16);
+    let mut scanning = Scanning::new(mailbox);
+    // when to try the targets of networks at their limit again
+    let mut blocked: Option<Instant> = None;
+
+    let mut events = [EpollEvent::empty(); 16];
     let mut recvbuf: [u8; 1024] = [0; 1024];
 
-    'outer: loop {
-        let mut done = vec![];
-        vvprintln!("polling...");
-        let npoll = poll(&mut pollfds, poll_timeout).expect("poll(2) failed");
-        if npoll > 0 {
+    loop {
+        // fill the free concurrent slots from the shared queue,
+        // while blocked only once a slot freed up or the retry is due
+        if blocked.is_none_or(|retry| Instant::now() >= retry) {
+            while scanning.states.len() < options.concurrent
+                && scanning.add_target(targets, options, fd4, fd6) {}
+            // the targets left are of networks at their limit, try again soon
+            blocked = (scanning.states.len() < options.concurrent && !targets.is_empty())
+                .then(|| Instant::now() + BLOCKED_RETRY);
+        }
+        if scanning.states.is_empty() && blocked.is_none() {
+            break;
+        }
+
+        let wakeup = scanning.deadlines.peek().map(|d| d.when).into_iter().chain(blocked).min();
+        let timeout = wakeup.map_or(PollTimeout::NONE, poll_timeout);
+        vvprintln!("waiting...");
+        let nevents = match epoll.wait(&mut events, timeout) {
+            Ok(n) => n,
+            Err(Errno::EINTR) => 0,
+            Err(e) => panic!("epoll_wait(2) failed: {e}"),
+        };
+
+        // one datagram per ready socket, epoll reports the socket again if there is more
+        for event in &events[..nevents] {
+            if event.data() == 0 {
+                for (src, raw) in mailbox.unwrap().take() {
+                    if scanning.handle_packet(src, &raw, fd4, fd6).is_err() {
+                        vprintln!("{src} finished before its forwarded packet was handled");
+                    }
+                }
+                continue;
+            }
+
             // the src ip will be mapped to a [SockAddrInet]
-            let recvfromres;
-            if pollfds[0].any() == Some(true) {
-                recvfromres = recvfrom::<SockaddrIn>(sockfd4.as_raw_fd(), &mut recvbuf)
-                    .map(|(n, osrc)| (n, osrc.map(|src| SockAddrInet::IPv4(src))));
-            } else if pollfds[1].any() == Some(true) {
-                recvfromres = recvfrom::<SockaddrIn6>(sockfd6.as_raw_fd(), &mut recvbuf)
-                    .map(|(n, osrc)| (n, osrc.map(|src| SockAddrInet::IPv6(src))));
+            let recvfromres = if event.data() == 4 {
+                recvfrom::<SockaddrIn>(fd4, &mut recvbuf)
+                    .map(|(n, osrc)| (n, osrc.map(|src| SockAddrInet::IPv4(src))))
             } else {
-                unreachable!()
-            }
+                recvfrom::<SockaddrIn6>(fd6, &mut recvbuf)
+                    .map(|(n, osrc)| (n, osrc.map(|src| SockAddrInet::IPv6(src))))
+            };
             match recvfromres {
                 Ok((nread, Some(src))) => {
-                    let pkt_option = packets::parse(&recvbuf[0..nread]);
-                    if pkt_option.is_none() {
-                        vprintln!("failed to parse {nread} byte pkt from {src}");
+                    let raw = &recvbuf[0..nread];
+                    let Err(target) = scanning.handle_packet(src, raw, fd4, fd6) else {
                         continue;
-                    }
-                    let pkt = pkt_option.unwrap();
-                    vvprintln!("{nread} bytes from {src}");
-                    vvprintln!("{pkt:?}");
-
-                    match states.get_mut(&src) {
-                        Some(state) => {
-                            // save packet
-                            state.pkts_received.push(pkt.clone());
-
-                            // TODO handle DENY and RSTR
-                            if let AnyNTPPacket::Standard(pkt) = pkt.clone() {
-                                if pkt.is_kod() && pkt.refidstr() == Some("RATE") {
-                                    vprintln!("{} Kiss o' Death RATE received", state.address);
-                                    state.rate_kod_received = true;
-                                    state.handle_rate_kod();
-                                } else if pkt.is_kod() && (pkt.refidstr() == Some("DENY") || pkt.refidstr() == Some("RSTR") ) {
-                                    eprintln!("{} Kiss o' Death {} received, quitting", state.address, pkt.refidstr().unwrap());
-                                    state.current_type = ScanType::Done;
-                                }
-                            }
-                            let scanstatus = state.recpkt(&pkt);
-                            if matches!(scanstatus, ScanTypeStatus::Done) {
-                                state.start_next_scan();
-                            }
-                            if matches!(state.current_type, ScanType::Done) {
-                                done.push(state.address);
-                            }
-                            state.flush(state.choose_sock(sockfd4.as_raw_fd(), sockfd6.as_raw_fd())).expect("error flushing");
-                        },
-                        None => {
-                            eprintln!("received packet from {src}, which isn't part of the target list???");
-                        },
+                    };
+                    if !mailbox.is_some_and(|m| m.forward(target, src, raw)) {
+                        eprintln!("received packet from {src}, which isn't part of the target list???");
                     }
                 },
                 Err(e) => println!("received errno {e:?} from recvfrom"),
                 _ => unreachable!(),
             }
-        } else {
-            vvprintln!("poll timeout");
-            for (addr, state) in states.iter_mut() {
-                if state.queue.is_empty() {
-                    let scanstatus = state.handle_timeout();
-                    if matches!(scanstatus, ScanTypeStatus::Done) {
-                        state.start_next_scan();
-                        if matches!(state.current_type, ScanType::Done) {
-                            done.push(state.address);
-                        }
-                    }
-                }
-                state.flush(state.choose_sock(sockfd4.as_raw_fd(), sockfd6.as_raw_fd())).expect("error flushing");
-            }
         }
-        for a in done {
-            tx.send(states.remove(&a).unwrap().to_result()).unwrap();
-
-            // potentially add a new target
-            if i < targets.len() {
-                let mut new_state = ScanState::new(targets[i], maxretries, spread, identify);
-                if states.contains_key(&new_state.address) {
-                    eprintln!("duplicate address {}", new_state.address);
-                } else {
-                    vvprintln!("added {} to concurrent targets", new_state.address);
-                    new_state.start_next_scan();
-                    new_state.flush(new_state.choose_sock(sockfd4.as_raw_fd(), sockfd6.as_raw_fd())).expect("error flushing");
-                    states.insert(new_state.address, new_state);
-                }
-                i += 1;
+
+        scanning.wake_due(fd4, fd6);
+
+        for a in mem::take(&mut scanning.done) {
+            let Some(state) = scanning.remove(a) else {
+                continue;
+            };
+            targets.done(a);
+            // a network may have a free slot again
+            blocked = None;
+            state.finished();
+            if tx.send(state.to_result()).is_err() {
+                // nobody is listening anymore
+                return;
             }
         }
-        
-        if states.is_empty() {
-            break;
-        }
-
     }
 
     vprintln!("a thread finished");
 
 }
+
+#[cfg(test)]
+fn identify_state(target: SockAddrInet) -> ScanState {
+    let options = ScanOptions {
+        retries: 1,
+        concurrent: 1,
+        polltimeout: 1000,
+        min_timeout: 500,
+        spread: None,
+        source_port: None,
+        politeness: None,
+        progress: None,
+        probes: crate::probe::select(&["identify".to_string()]).unwrap(),
+        parallel_probes: false,
+        probe_config: ProbeConfig::default(),
+    };
+    let mut state = ScanState::new(target, &options);
+    state.start_probes();
+    state
+}
+
+#[cfg(test)]
+fn reply_to(request: &AnyNTPPacket) -> packets::NTPPacket {
+    let mut reply = packets::NTPPacket::empty();
+    reply.version = 4;
+    reply.mode = 4;
+    reply.org = request.as_standard().unwrap().xmt;
+    reply
+}
+
+#[test]
+fn attribute_other_source() {
+    let target = SockAddrInet::new("192.0.2.1".parse().unwrap(), 123);
+    let mut state = identify_state(target);
+    let (request, _) = state.next_packet().unwrap();
+    let mut index = Correlations::default();
+    index.insert(request.request_id().unwrap(), target);
+    let mut states = HashMap::new();
+    states.insert(target, state);
+
+    let mut reply = reply_to(&request);
+    let other = SockAddrInet::new("198.51.100.7".parse().unwrap(), 123);
+    let (attributed, _) = attribute(&states, |id| index.find(id), other, &reply.pack()).unwrap();
+    assert_eq!(attributed, target);
+
+    // two targets sent the same request, the reply is of neither
+    let second = SockAddrInet::new("192.0.2.2".parse().unwrap(), 123);
+    index.insert(request.request_id().unwrap(), second);
+    let (attributed, _) = attribute(&states, |id| index.find(id), other, &reply.pack()).unwrap();
+    assert_eq!(attributed, other);
+    index.forget(second, [request.request_id().unwrap()]);
+    assert_eq!(index.find(&request.request_id().unwrap()), Some(target));
+
+    reply.org ^= 1;
+    let (attributed, _) = attribute(&states, |id| index.find(id), other, &reply.pack()).unwrap();
+    assert_eq!(attributed, other);
+}
+
+#[test]
+fn rto_from_rtt() {
+    let target = SockAddrInet::new("192.0.2.1".parse().unwrap(), 123);
+    let mut state = identify_state(target);
+    let (first, _) = state.next_packet().unwrap();
+    let (second, _) = state.next_packet().unwrap();
+    assert_eq!(state.rto(), Duration::from_secs(1));
+
+    // a retransmitted request is no measurement
+    state.queue.push_front(second.clone());
+    state.next_packet();
+    let reply = reply_to(&second);
+    state.receive(target, &reply.pack(), AnyNTPPacket::Standard(reply));
+    assert!(state.srtt.is_none());
+
+    let reply = reply_to(&first);
+    state.receive(target, &reply.pack(), AnyNTPPacket::Standard(reply));
+    assert!(state.srtt.is_some());
+    assert_eq!(state.rto(), Duration::from_millis(500));
+    state.backoff = 2;
+    assert_eq!(state.rto(), Duration::from_secs(2));
+}
+
+#[test]
+fn deadlines_skip_stale() {
+    let sink = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let target = SockAddrInet::new("127.0.0.1".parse().unwrap(), sink.local_addr().unwrap().port());
+    let mut scanning = Scanning::new(None);
+    let mut state = identify_state(target);
+    while state.next_packet().is_some() {}
+
+    // due a second ago, then rescheduled by a later exchange
+    state.last_exchange = Instant::now() - Duration::from_secs(2);
+    schedule(&mut scanning.deadlines, &mut state);
+    state.last_exchange = Instant::now();
+    schedule(&mut scanning.deadlines, &mut state);
+    schedule(&mut scanning.deadlines, &mut state);
+    assert_eq!(scanning.deadlines.len(), 2);
+    assert_eq!(state.wakeup, Some(state.last_exchange + Duration::from_secs(1)));
+    scanning.states.insert(target, state);
+    scanning.wake_due(sock.as_raw_fd(), sock.as_raw_fd());
+    assert_eq!(scanning.deadlines.len(), 1);
+    assert_eq!(scanning.states[&target].backoff, 0);
+
+    // woken once its timeout passed
+    let state = scanning.states.get_mut(&target).unwrap();
+    state.last_exchange = Instant::now() - Duration::from_secs(2);
+    schedule(&mut scanning.deadlines, state);
+    scanning.wake_due(sock.as_raw_fd(), sock.as_raw_fd());
+    assert_eq!(scanning.states[&target].backoff, 1);
+
+    assert_eq!(poll_timeout(Instant::now() + Duration::from_micros(100)), PollTimeout::from(1u8));
+}
@@ -0,0 +1,217 @@
+//! The scan engine on tokio, runs the same [ScanState]s as [crate::scan::start_thread]
+//! but waits on a [UdpSocket] and tokio timers instead of blocking in poll(2).
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::time::Instant;
+
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::scan::attribute;
+use crate::scan::BLOCKED_RETRY;
+use crate::scan::Correlations;
+use crate::scan::ScanOptions;
+use crate::scan::ScanResult;
+use crate::scan::ScanState;
+use crate::scan::TargetQueue;
+use crate::socket::SockAddrInet;
+
+/// Scan the targets in a tokio task, must be called from within a tokio runtime
+pub fn scan_stream(targets: Vec<SockAddrInet>, options: ScanOptions) -> ReceiverStream<ScanResult> {
+    let (tx, rx) = mpsc::channel(options.concurrent.max(1));
+    tokio::spawn(scan_task(tx, targets, options));
+    ReceiverStream::new(rx)
+}
+
+/// Wait for a packet, forever if there is no socket for this address family
+async fn recv(sock: Option<&UdpSocket>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match sock {
+        Some(sock) => sock.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn flush(state: &mut ScanState, index: &mut Correlations, sock4: Option<&UdpSocket>, sock6: Option<&UdpSocket>) {
+    while let Some((msg, address)) = state.next_packet() {
+        if let Some(id) = msg.request_id() {
+            index.insert(id, state.address);
+        }
+        let sock = match address {
+            SockAddrInet::IPv4(_) => sock4,
+            SockAddrInet::IPv6(_) => sock6,
+        };
+        let Some(sock) = sock else {
+            eprintln!("{} no socket for this address family", state.address);
+            continue;
+        };
+        if let Err(e) = sock.send_to(&msg.pack(), SocketAddr::new(address.ip(), address.port())).await {
+            eprintln!("{} error sending packet: {}", state.address, e);
+        }
+    }
+}
+
+/// Take the next target from the queue, None if there is none to take
+fn add_target(states: &mut HashMap<SockAddrInet, ScanState>, targets: &TargetQueue, options: &ScanOptions) -> Option<SockAddrInet> {
+    loop {
+        let address = targets.next()?;
+        if states.contains_key(&address) {
+            eprintln!("duplicate address {}", address);
+            targets.done(address);
+            if let Some(progress) = &options.progress {
+                progress.skipped();
+            }
+            continue;
+        }
+        vvprintln!("added {} to concurrent targets", address);
+        let mut state = ScanState::new(address, options);
+        state.started();
+        state.start_probes();
+        states.insert(address, state);
+        return Some(address);
+    }
+}
+
+async fn scan_task(tx: mpsc::Sender<ScanResult>, targets: Vec<SockAddrInet>, options: ScanOptions) {
+    let port = options.source_port.unwrap_or(0);
+    let sock4 = UdpSocket::bind(("0.0.0.0", port)).await.ok();
+    let sock6 = UdpSocket::bind(("::", port)).await.ok();
+    if sock4.is_none() && sock6.is_none() {
+        eprintln!("failed to bind a UDP socket");
+        return;
+    }
+
+    let targets = TargetQueue::new(targets, options.politeness.clone());
+    let mut states: HashMap<SockAddrInet, ScanState> = HashMap::new();
+    let mut index = Correlations::default();
+    let mut buf4 = [0; 1024];
+    let mut buf6 = [0; 1024];
+
+    // when to try the targets of networks at their limit again
+    let mut blocked: Option<Instant> = None;
+
+    loop {
+        // fill the free concurrent slots, while blocked only once a slot freed up or the retry is due
+        if blocked.is_none_or(|retry| Instant::now() >= retry) {
+            while states.len() < options.concurrent
+                && let Some(address) = add_target(&mut states, &targets, &options) {
+                flush(states.get_mut(&address).unwrap(), &mut index, sock4.as_ref(), sock6.as_ref()).await;
+            }
+            blocked = (states.len() < options.concurrent && !targets.is_empty())
+                .then(|| Instant::now() + BLOCKED_RETRY);
+        }
+        if states.is_empty() && blocked.is_none() {
+            break;
+        }
+
+        // wake up when a target times out, a backed off target may send again or the retry is due
+        let wait = states.values()
+            .map(|s| s.next_wakeup())
+            .chain(blocked)
+            .min()
+            .map_or(Duration::ZERO, |wakeup| wakeup.saturating_duration_since(Instant::now()));
+
+        let mut touched = vec![];
+        tokio::select! {
+            res = recv(sock4.as_ref(), &mut buf4) => {
+                if let Ok((n, src)) = res {
+                    touched.extend(handle(&mut states, &index, src, &buf4[..n]));
+                }
+            },
+            res = recv(sock6.as_ref(), &mut buf6) => {
+                if let Ok((n, src)) = res {
+                    touched.extend(handle(&mut states, &index, src, &buf6[..n]));
+                }
+            },
+            _ = tokio::time::sleep(wait) => {
+                let now = Instant::now();
+                for state in states.values_mut() {
+                    if state.queue.is_empty() && now >= state.idle_deadline() {
+                        vvprintln!("{} timeout", state.address);
+                        state.idle();
+                    }
+                    if state.poll_deadline().is_some_and(|poll| now >= poll) {
+                        state.poll();
+                    }
+                }
+                touched.extend(states.keys().copied());
+            },
+        }
+
+        for address in touched {
+            let Some(state) = states.get_mut(&address) else {
+                continue;
+            };
+            flush(state, &mut index, sock4.as_ref(), sock6.as_ref()).await;
+            if !state.is_done() {
+                continue;
+            }
+            let state = states.remove(&address).unwrap();
+            index.forget(address, state.sent_ids());
+            targets.done(address);
+            blocked = None;
+            state.finished();
+            let result = state.to_result();
+            if tx.send(result).await.is_err() {
+                // the stream was dropped
+                return;
+            }
+        }
+    }
+
+    vprintln!("an async scan finished");
+}
+
+/// Pass a received packet to its target, returns the target
+fn handle(states: &mut HashMap<SockAddrInet, ScanState>, index: &Correlations, src: SocketAddr, raw: &[u8]) -> Option<SockAddrInet> {
+    let src = SockAddrInet::new(src.ip(), src.port());
+    let (target, pkt) = attribute(states, |id| index.find(id), src, raw)?;
+    let Some(state) = states.get_mut(&target) else {
+        eprintln!("received packet from {src}, which isn't part of the target list???");
+        return None;
+    };
+    state.receive(src, raw, pkt);
+    Some(target)
+}
+
+#[tokio::test]
+async fn scan_stream_identify() {
+    use tokio_stream::StreamExt;
+    use crate::packets::NTPPacket;
+
+    // a server answering every mode 3 request with its own version
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut buf = [0; 1024];
+        loop {
+            let (n, client) = server.recv_from(&mut buf).await.unwrap();
+            let Some(request) = NTPPacket::parse(&buf[..n]) else { continue };
+            if !(1..=4).contains(&request.version) {
+                continue;
+            }
+            let mut response = NTPPacket::empty();
+            response.version = request.version;
+            response.mode = 4;
+            response.stratum = 2;
+            response.org = request.xmt;
+            server.send_to(&response.pack(), client).await.unwrap();
+        }
+    });
+
+    let results: Vec<ScanResult> = crate::Scanner::new()
+        .port(port)
+        .target("127.0.0.1".parse().unwrap())
+        .probes(&["identify"])
+        .poll_timeout(100)
+        .stream()
+        .unwrap()
+        .collect()
+        .await;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].versions.get(&4), Some(&Some(4)));
+    assert_eq!(results[0].versions.get(&5), Some(&None));
+    assert_eq!(results[0].stratum, Some(2));
+}
@@ -0,0 +1,254 @@
+//! Builder for running a scan from other programs, the `ntpscan` command is a wrapper around it.
+use std::mem;
+use std::net::IpAddr;
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use crate::dispatch::Dispatcher;
+use crate::politeness::Politeness;
+use crate::probe;
+use crate::progress::Progress;
+use crate::probe::ProbeConfig;
+use crate::scan;
+use crate::scan::ScanOptions;
+use crate::scan::ScanResult;
+use crate::socket::SockAddrInet;
+
+pub struct Scanner {
+    targets: Vec<SockAddrInet>,
+    port: u16,
+    threads: usize,
+    retries: u32,
+    concurrent: usize,
+    polltimeout: u32,
+    min_timeout: u32,
+    spread: Option<u64>,
+    source_port: Option<u16>,
+    v4_prefix: u8,
+    v6_prefix: u8,
+    targets_per_prefix: Option<usize>,
+    prefix_rate: Option<u32>,
+    progress: Option<Arc<Progress>>,
+    probes: Vec<String>,
+    parallel_probes: bool,
+    probe_config: ProbeConfig,
+}
+
+impl Default for Scanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scanner {
+    /// A scanner with the same defaults as the command line
+    pub fn new() -> Self {
+        Self {
+            targets: vec![],
+            port: 123,
+            threads: 2,
+            retries: 1,
+            concurrent: 1000,
+            polltimeout: 1000,
+            min_timeout: 500,
+            spread: None,
+            source_port: None,
+            v4_prefix: 24,
+            v6_prefix: 48,
+            targets_per_prefix: None,
+            prefix_rate: None,
+            progress: None,
+            probes: probe::DEFAULT_PROBES.split(',').map(String::from).collect(),
+            parallel_probes: false,
+            probe_config: ProbeConfig::default(),
+        }
+    }
+
+    pub fn target(mut self, ip: IpAddr) -> Self {
+        self.targets.push(SockAddrInet::new(ip, self.port));
+        self
+    }
+
+    pub fn targets(mut self, ips: impl IntoIterator<Item = IpAddr>) -> Self {
+        for ip in ips {
+            self = self.target(ip);
+        }
+        self
+    }
+
+    /// The port ntp requests are sent to, applies to the targets added after it
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// The amount of threads the targets are divided over
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// How often to retry sending a packet
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// How many targets a thread scans at the same time
+    pub fn concurrency(mut self, concurrent: usize) -> Self {
+        self.concurrent = concurrent.max(1);
+        self
+    }
+
+    /// How long to wait for a reply (in ms), until the round trip time of a target is known.
+    /// After that the timeout is adapted to it, see [ScanState::rto](crate::scan::ScanState::rto)
+    pub fn poll_timeout(mut self, ms: u32) -> Self {
+        self.polltimeout = ms;
+        self
+    }
+
+    /// The lowest timeout (in ms) once it is adapted to the round trip time
+    pub fn min_timeout(mut self, ms: u32) -> Self {
+        self.min_timeout = ms;
+        self
+    }
+
+    /// Interval in-between sent packets in secs
+    pub fn spread(mut self, secs: Option<u64>) -> Self {
+        self.spread = secs;
+        self
+    }
+
+    /// Send from this port on every thread instead of a random port per thread,
+    /// replies arriving at the wrong thread are forwarded to the right one
+    pub fn source_port(mut self, port: Option<u16>) -> Self {
+        self.source_port = port;
+        self
+    }
+
+    /// The prefix lengths of the networks the targets are interleaved by and
+    /// [Self::targets_per_prefix] and [Self::prefix_rate] apply to, /24 and /48 by default
+    pub fn prefix_lengths(mut self, v4: u8, v6: u8) -> Self {
+        self.v4_prefix = v4;
+        self.v6_prefix = v6;
+        self
+    }
+
+    /// How many targets of a network are scanned at the same time
+    pub fn targets_per_prefix(mut self, max: Option<usize>) -> Self {
+        self.targets_per_prefix = max;
+        self
+    }
+
+    /// How many packets per second are sent to a network
+    pub fn prefix_rate(mut self, rate: Option<u32>) -> Self {
+        self.prefix_rate = rate;
+        self
+    }
+
+    /// Counters the scan updates, see [Progress::report]
+    pub fn progress(mut self, progress: Option<Arc<Progress>>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// The probes to run on every target in order, see [probe::PROBES]
+    pub fn probes(mut self, names: &[&str]) -> Self {
+        self.probes = names.iter().map(|n| n.to_string()).collect();
+        self
+    }
+
+    /// Run the probes of a target at the same time instead of one after another
+    pub fn parallel_probes(mut self, parallel: bool) -> Self {
+        self.parallel_probes = parallel;
+        self
+    }
+
+    /// Packet contents and keys used by the probes
+    pub fn probe_config(mut self, config: ProbeConfig) -> Self {
+        self.probe_config = config;
+        self
+    }
+
+    /// Fails if an unknown probe was selected
+    fn options(&self) -> anyhow::Result<ScanOptions> {
+        let probes = probe::select(&self.probes)?;
+        anyhow::ensure!(!probes.is_empty(), "no probes selected");
+        Ok(ScanOptions {
+            retries: self.retries,
+            concurrent: self.concurrent,
+            polltimeout: self.polltimeout,
+            min_timeout: self.min_timeout,
+            spread: self.spread,
+            source_port: self.source_port,
+            politeness: self.politeness(),
+            progress: self.progress.clone(),
+            probes,
+            parallel_probes: self.parallel_probes,
+            probe_config: self.probe_config.clone(),
+        })
+    }
+
+    /// Only needed when the networks are limited
+    fn politeness(&self) -> Option<Arc<Politeness>> {
+        let politeness = Politeness::new(self.v4_prefix, self.v6_prefix)
+            .max_targets(self.targets_per_prefix)
+            .rate(self.prefix_rate);
+        (politeness.max_targets.is_some() || politeness.rate.is_some()).then(|| Arc::new(politeness))
+    }
+
+    /// The targets in the order they are scanned, interleaved by network
+    fn take_targets(&mut self) -> Vec<SockAddrInet> {
+        Politeness::new(self.v4_prefix, self.v6_prefix).interleave(mem::take(&mut self.targets))
+    }
+
+    /// Start the scan threads, the results arrive as the targets finish.
+    /// Fails if an unknown probe was selected
+    pub fn start(mut self) -> anyhow::Result<ScanResults> {
+        let mut options = self.options()?;
+        let (tx, rx) = mpsc::channel();
+        let threads = self.threads.min(self.targets.len()).max(1);
+        // spread the first targets over the threads, the rest is taken as slots free up
+        options.concurrent = options.concurrent.min(self.targets.len().div_ceil(threads)).max(1);
+        let targets = Arc::new(scan::TargetQueue::new(self.take_targets(), options.politeness.clone()));
+        let dispatcher = match options.source_port {
+            Some(_) => Some(Dispatcher::new(threads)?),
+            None => None,
+        };
+        for i in 0..threads {
+            let mailbox = dispatcher.as_ref().map(|d| d.mailbox(i));
+            scan::start_thread(targets.clone(), options.clone(), tx.clone(), mailbox);
+        }
+        vprintln!("Scanning {} targets using {} threads each scanning at most {} targets concurrently", targets.len(), threads, self.concurrent);
+        Ok(ScanResults { rx })
+    }
+
+    /// Scan in a tokio task instead of threads, the number of threads is ignored.
+    /// Has to be called from within a tokio runtime
+    #[cfg(feature = "async")]
+    pub fn stream(mut self) -> anyhow::Result<impl tokio_stream::Stream<Item = ScanResult>> {
+        let options = self.options()?;
+        Ok(crate::scan_async::scan_stream(self.take_targets(), options))
+    }
+
+    /// Run the scan to completion, calling `callback` for every result
+    pub fn run(self, mut callback: impl FnMut(ScanResult)) -> anyhow::Result<()> {
+        for res in self.start()? {
+            callback(res);
+        }
+        Ok(())
+    }
+}
+
+/// The results of a running scan, ends when every thread is done
+pub struct ScanResults {
+    rx: mpsc::Receiver<ScanResult>,
+}
+
+impl Iterator for ScanResults {
+    type Item = ScanResult;
+
+    fn next(&mut self) -> Option<ScanResult> {
+        self.rx.recv().ok()
+    }
+}
@@ -1,4 +1,6 @@
 use std::fmt::Display;
+use std::net::IpAddr;
+use std::os::fd::AsRawFd;
 use std::os::fd::OwnedFd;
 use nix::errno::Errno;
 use nix::sys::socket::*;
@@ -16,6 +18,23 @@ pub fn setup_socket(family: AddressFamily) -> Result<OwnedFd, Errno> {
     )
 }
 
+/// Like [setup_socket] but bound to `port` with SO_REUSEPORT,
+/// so every scan thread can have a socket on the same port
+pub fn setup_shared_socket(family: AddressFamily, port: u16) -> Result<OwnedFd, Errno> {
+    let fd = setup_socket(family)?;
+    setsockopt(&fd, sockopt::ReusePort, &true)?;
+    let addr = match family {
+        AddressFamily::Inet6 => {
+            // otherwise it would collide with the IPv4 socket on the same port
+            setsockopt(&fd, sockopt::Ipv6V6Only, &true)?;
+            SockAddrInet::new(IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED), port)
+        },
+        _ => SockAddrInet::new(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), port),
+    };
+    bind(fd.as_raw_fd(), addr.as_sockaddr_like())?;
+    Ok(fd)
+}
+
 #[derive(Clone, Copy, Eq, Hash, PartialEq, Debug)]
 pub enum SockAddrInet {
     IPv4(SockaddrIn),
@@ -29,6 +48,35 @@ impl SockAddrInet {
             SockAddrInet::IPv6(addr) => addr,
         }
     }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            SockAddrInet::IPv4(addr) => addr.port(),
+            SockAddrInet::IPv6(addr) => addr.port(),
+        }
+    }
+
+    pub fn new(ip: IpAddr, port: u16) -> Self {
+        match ip {
+            IpAddr::V4(ip) => SockAddrInet::IPv4(SockaddrIn::from(std::net::SocketAddrV4::new(ip, port))),
+            IpAddr::V6(ip) => SockAddrInet::IPv6(SockaddrIn6::from(std::net::SocketAddrV6::new(ip, port, 0, 0))),
+        }
+    }
+
+    /// The same address on another port
+    pub fn with_port(&self, port: u16) -> Self {
+        match self {
+            SockAddrInet::IPv4(addr) => SockAddrInet::IPv4(SockaddrIn::from(std::net::SocketAddrV4::new(addr.ip(), port))),
+            SockAddrInet::IPv6(addr) => SockAddrInet::IPv6(SockaddrIn6::from(std::net::SocketAddrV6::new(addr.ip(), port, addr.flowinfo(), addr.scope_id()))),
+        }
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            SockAddrInet::IPv4(addr) => IpAddr::V4(addr.ip()),
+            SockAddrInet::IPv6(addr) => IpAddr::V6(addr.ip()),
+        }
+    }
 }
 
 impl Display for SockAddrInet {
@@ -0,0 +1,288 @@
+//! A stateless discovery sweep like zmap, for when the targets are too many to keep a [crate::scan::ScanState] for.
+//! The requests go out through a raw socket with their source port, `xmt` and sequence derived from
+//! a keyed hash of the target, so a reply is validated by hashing its source again.
+//! Mode 7 replies echo nothing of the request, they are only validated by the port,
+//! which leaves about 15 bits for a spoofed reply to guess.
+//! The hosts that respond can then be scanned by the [crate::Scanner].
+//! Only IPv4, and raw sockets require CAP_NET_RAW.
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddrV4;
+use std::os::fd::AsFd;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use aes::Aes128;
+use anyhow::Context;
+use cmac::Cmac;
+use cmac::Mac as _;
+use nix::poll::poll;
+use nix::poll::PollFd;
+use nix::poll::PollFlags;
+use nix::poll::PollTimeout;
+use nix::sys::socket::*;
+use crate::packets;
+use crate::packets::AnyNTPPacket;
+use crate::packets::NTPPacket;
+use crate::packets::NtpControlMessage;
+use crate::packets::NtpdPrivatePacket;
+use crate::socket::SockAddrInet;
+
+/// What is sent to a target, all derived from the key and the target
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cookie {
+    /// the source port of the requests
+    pub port: u16,
+    /// of the mode 3 request
+    pub xmt: u64,
+    /// of the mode 6 request
+    pub sequence: u16,
+}
+
+/// The secret of a sweep, AES-CMAC of the target
+pub struct CookieKey([u8; 16]);
+
+impl CookieKey {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    pub fn cookie(&self, target: SocketAddrV4) -> Cookie {
+        let mut mac = <Cmac<Aes128> as cmac::Mac>::new_from_slice(&self.0).expect("cmac keys are 16 bytes");
+        mac.update(&target.ip().octets());
+        mac.update(&target.port().to_be_bytes());
+        let h = mac.finalize().into_bytes();
+        Cookie {
+            // the ephemeral range
+            port: 32768 + u16::from_be_bytes([h[0], h[1]]) % 28232,
+            xmt: u64::from_be_bytes(h[2..10].try_into().unwrap()),
+            sequence: u16::from_be_bytes([h[10], h[11]]),
+        }
+    }
+}
+
+/// What kind of request a target answered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Discovery {
+    Client { version: u8, stratum: u8 },
+    Control,
+    /// a single packet of a monlist response, or a mode 7 error.
+    /// Weakly validated, only by the port the request was sent from
+    Monlist { bytes: usize, error: u16 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub address: SockAddrInet,
+    pub discovery: Discovery,
+}
+
+impl Hit {
+    pub fn summary(&self) -> String {
+        match self.discovery {
+            Discovery::Client { version, stratum } => format!("{} answered mode 3 (version {version}, stratum {stratum})", self.address),
+            Discovery::Control => format!("{} answered mode 6", self.address),
+            Discovery::Monlist { error: 0, bytes } => format!("{} answered monlist ({bytes} bytes)", self.address),
+            Discovery::Monlist { error, .. } => format!("{} answered mode 7 with error {error}", self.address),
+        }
+    }
+}
+
+pub struct Sweep {
+    targets: Vec<SocketAddrV4>,
+    port: u16,
+    /// packets per second
+    rate: Option<u32>,
+    /// how long to wait for replies after the last request
+    cooldown: Duration,
+    key: CookieKey,
+}
+
+impl Default for Sweep {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sweep {
+    pub fn new() -> Self {
+        Self {
+            targets: vec![],
+            port: 123,
+            rate: None,
+            cooldown: Duration::from_secs(2),
+            key: CookieKey::random(),
+        }
+    }
+
+    /// The port requests are sent to, applies to the targets added after it
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// IPv6 targets are skipped
+    pub fn targets(mut self, ips: impl IntoIterator<Item = IpAddr>) -> Self {
+        for ip in ips {
+            match ip {
+                IpAddr::V4(ip) => self.targets.push(SocketAddrV4::new(ip, self.port)),
+                IpAddr::V6(ip) => vprintln!("{ip} skipped, the stateless sweep is IPv4 only"),
+            }
+        }
+        self
+    }
+
+    /// Packets per second, unlimited if None
+    pub fn rate(mut self, rate: Option<u32>) -> Self {
+        self.rate = rate.filter(|&r| r > 0);
+        self
+    }
+
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// The requests sent to a target, the mode 3, mode 6 and monlist request
+    fn requests(&self, cookie: Cookie) -> [AnyNTPPacket; 3] {
+        let mut client = NTPPacket::empty();
+        client.version = 4;
+        client.mode = 3;
+        client.xmt = cookie.xmt;
+        let mut control = NtpControlMessage::empty();
+        control.version = 2;
+        control.opcode = packets::control::CTL_OP_READVAR;
+        control.sequence = cookie.sequence;
+        let mut monlist = NtpdPrivatePacket::empty();
+        monlist.reqcode = packets::private::REQ_MON_GETLIST_1;
+        [AnyNTPPacket::Standard(client), AnyNTPPacket::Control(control), AnyNTPPacket::Private(monlist)]
+    }
+
+    fn send_all<T: AsRawFd>(&self, sock: &T) {
+        let start = Instant::now();
+        let mut sent = 0;
+        for &target in &self.targets {
+            let cookie = self.key.cookie(target);
+            for request in self.requests(cookie) {
+                if let Some(rate) = self.rate {
+                    let at = start + Duration::from_secs(sent) / rate;
+                    thread::sleep(at.saturating_duration_since(Instant::now()));
+                }
+                let datagram = udp_datagram(cookie.port, target.port(), &request.pack());
+                if let Err(e) = sendto(sock.as_raw_fd(), &datagram, &SockaddrIn::from(target), MsgFlags::empty()) {
+                    eprintln!("{target} error sending packet: {e}");
+                }
+                sent += 1;
+            }
+        }
+        vprintln!("stateless sweep sent {sent} packets in {}ms", start.elapsed().as_millis());
+    }
+
+    /// Validate a captured IPv4 packet, None if it is not a reply to the sweep
+    fn validate(&self, ip_packet: &[u8]) -> Option<Hit> {
+        let (src, dst_port, payload) = parse_udp(ip_packet)?;
+        let cookie = self.key.cookie(src);
+        if dst_port != cookie.port {
+            return None;
+        }
+        let discovery = match packets::parse(payload)? {
+            AnyNTPPacket::Standard(pkt) if pkt.mode == 4 && pkt.org == cookie.xmt => Discovery::Client { version: pkt.version, stratum: pkt.stratum },
+            AnyNTPPacket::Control(pkt) if pkt.response && pkt.sequence == cookie.sequence => Discovery::Control,
+            // nothing to compare but the port
+            AnyNTPPacket::Private(pkt) if pkt.response => Discovery::Monlist { bytes: payload.len(), error: pkt.error },
+            _ => return None,
+        };
+        Some(Hit { address: SockAddrInet::IPv4(SockaddrIn::from(src)), discovery })
+    }
+
+    /// Send the requests to every target and call `callback` for every valid reply,
+    /// returns `cooldown` after the last request was sent
+    pub fn run(&self, mut callback: impl FnMut(Hit)) -> anyhow::Result<()> {
+        let sock = socket(AddressFamily::Inet, SockType::Raw, SockFlag::SOCK_CLOEXEC, SockProtocol::Udp)
+            .context("failed to open a raw socket, this requires CAP_NET_RAW")?;
+        vprintln!("stateless sweep of {} targets", self.targets.len());
+        let sending = AtomicBool::new(true);
+        thread::scope(|s| {
+            s.spawn(|| {
+                self.send_all(&sock);
+                sending.store(false, Ordering::Release);
+            });
+
+            let mut buf = [0; 65536];
+            let mut until = None;
+            loop {
+                if until.is_none() && !sending.load(Ordering::Acquire) {
+                    until = Some(Instant::now() + self.cooldown);
+                }
+                if until.is_some_and(|until| Instant::now() >= until) {
+                    break;
+                }
+                let mut fds = [PollFd::new(sock.as_fd(), PollFlags::POLLIN)];
+                if poll(&mut fds, PollTimeout::from(100u8)).unwrap_or(0) == 0 {
+                    continue;
+                }
+                match recv(sock.as_raw_fd(), &mut buf, MsgFlags::empty()) {
+                    Ok(n) => if let Some(hit) = self.validate(&buf[..n]) {
+                        vvprintln!("{}", hit.summary());
+                        callback(hit);
+                    },
+                    Err(e) => eprintln!("received errno {e:?} from recv"),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// A UDP header before `payload`, the kernel adds the IP header.
+/// The checksum is left at zero, which means none for IPv4
+fn udp_datagram(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(8 + payload.len());
+    datagram.extend(src_port.to_be_bytes());
+    datagram.extend(dst_port.to_be_bytes());
+    datagram.extend((8 + payload.len() as u16).to_be_bytes());
+    datagram.extend([0, 0]);
+    datagram.extend(payload);
+    datagram
+}
+
+/// The source, destination port and payload of a UDP packet in an IPv4 packet
+fn parse_udp(ip_packet: &[u8]) -> Option<(SocketAddrV4, u16, &[u8])> {
+    let ihl = (*ip_packet.first()? & 0xf) as usize * 4;
+    if ip_packet[0] >> 4 != 4 || ihl < 20 || ip_packet.len() < ihl + 8 || ip_packet[9] != 17 {
+        return None;
+    }
+    let src_ip = Ipv4Addr::new(ip_packet[12], ip_packet[13], ip_packet[14], ip_packet[15]);
+    let udp = &ip_packet[ihl..];
+    let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+    let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+    let len = (u16::from_be_bytes([udp[4], udp[5]]) as usize).clamp(8, udp.len());
+    Some((SocketAddrV4::new(src_ip, src_port), dst_port, &udp[8..len]))
+}
+
+#[test]
+fn validate_cookie() {
+    let sweep = Sweep::new();
+    let target = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 123);
+    let cookie = sweep.key.cookie(target);
+    assert_eq!(cookie, sweep.key.cookie(target));
+
+    let mut reply = NTPPacket::empty();
+    reply.version = 4;
+    reply.mode = 4;
+    reply.stratum = 2;
+    reply.org = cookie.xmt;
+    let mut ip_packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0, 192, 0, 2, 1, 192, 0, 2, 2];
+    ip_packet.extend(udp_datagram(123, cookie.port, &reply.pack()));
+    let hit = sweep.validate(&ip_packet).unwrap();
+    assert_eq!(hit.discovery, Discovery::Client { version: 4, stratum: 2 });
+    assert_eq!(hit.address.to_string(), "192.0.2.1:123");
+
+    // another port than the one the target was sent from
+    let mut ip_packet = ip_packet[..20].to_vec();
+    ip_packet.extend(udp_datagram(123, cookie.port ^ 1, &reply.pack()));
+    assert!(sweep.validate(&ip_packet).is_none());
+}
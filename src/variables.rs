@@ -1,73 +1,148 @@
 //! in a previous revision this module was called version
+use crate::keys::Key;
 use crate::packets::AnyNTPPacket;
 use crate::packets::NtpControlMessage;
+use crate::probe::Probe;
+use crate::probe::ProbeConfig;
+use crate::scan::ScanResult;
 use crate::scan::ScanState;
 use crate::scan::ScanTypeStatus;
 
-pub struct VersionRequestStatus {
+/// Sends the mode 6 read variables request for association 0
+pub struct VariablesProbe {
     retries: u32,
+    sequence: u16,
+    variables: Option<Mode6Variables>,
+    versions: Vec<u8>,
+    key: Option<Key>,
 }
 
-impl VersionRequestStatus {
-    pub fn new() -> Self {
+pub struct Mode6Variables {
+    pub str: String,
+}
+
+impl VariablesProbe {
+    pub fn new(config: &ProbeConfig) -> Self {
         Self {
             retries: 0,
+            sequence: rand::random(),
+            variables: None,
+            versions: config.mode6_versions.clone(),
+            key: config.control_key.clone(),
         }
     }
-}
 
-pub struct Mode6Variables {
-    pub str: String,
+    fn queue_request(&self, state: &mut ScanState) {
+        for &version in &self.versions {
+            let mut msg = NtpControlMessage::empty();
+            msg.version = version;
+            msg.opcode = 2;
+            msg.sequence = self.sequence;
+            if let Some(key) = &self.key {
+                key.sign_control(&mut msg);
+            }
+            state.queue.push_back(AnyNTPPacket::Control(msg));
+        }
+    }
 }
 
-pub fn init(state: &mut ScanState) {
-    let mut msg = NtpControlMessage::empty();
-    msg.version = 3;
-    msg.opcode = 2;
-    state.queue.push_back(AnyNTPPacket::Control(msg));
-}
+impl Probe for VariablesProbe {
+    fn name(&self) -> &'static str {
+        "variables"
+    }
 
-pub fn receive(state: &mut ScanState, pkt: &AnyNTPPacket) -> ScanTypeStatus {
-    match pkt {
-        AnyNTPPacket::Control(pkt) => {
-            if pkt.opcode == 2 {
-                if pkt.response != true {
-                    vprintln!("{} (mode 6) received request instead of response, quitting", state.address);
-                    // it might've just echo'd our request
-                    return ScanTypeStatus::Done
-                }
-                if pkt.error == true {
-                    vprintln!("{} (mode 6) received error response", state.address);
-                    //return ScanTypeStatus::Done;
+    fn init(&mut self, state: &mut ScanState) {
+        self.queue_request(state);
+    }
+
+    fn accepts(&self, pkt: &AnyNTPPacket) -> bool {
+        pkt.as_control().is_some_and(|p| p.opcode == 2 && p.sequence == self.sequence)
+    }
+
+    fn receive(&mut self, state: &mut ScanState, pkt: &AnyNTPPacket) -> ScanTypeStatus {
+        match pkt {
+            AnyNTPPacket::Control(pkt) => {
+                if pkt.opcode == 2 {
+                    if pkt.response != true {
+                        vprintln!("{} (mode 6) received request instead of response, quitting", state.address);
+                        // it might've just echo'd our request
+                        return ScanTypeStatus::Done
+                    }
+                    if pkt.error == true {
+                        vprintln!("{} (mode 6) received error response", state.address);
+                        //return ScanTypeStatus::Done;
+                    }
+                    eprintln!("{} mode 6 variables response: {}", state.address, pkt.datastr().map(|s| s.trim_end_matches(char::is_whitespace))
+                        .unwrap_or("failed to convert to utf-8"));
+                    self.variables = Some(Mode6Variables {
+                        str: pkt.datastr().unwrap_or("failed to convert to utf-8").to_owned()
+                    });
+                    return ScanTypeStatus::Done;
+                } else {
+                    vvprintln!("{} (mode 6) variables command received response with other opcode than 2", state.address)
                 }
-                eprintln!("{} mode 6 variables response: {}", state.address, pkt.datastr().map(|s| s.trim_end_matches(char::is_whitespace))
-                    .unwrap_or("failed to convert to utf-8"));
-                state.mode6_variables = Some(Mode6Variables {
-                    str: pkt.datastr().unwrap_or("failed to convert to utf-8").to_owned()
-                });
-                return ScanTypeStatus::Done;
-            } else {
-                vvprintln!("{} (mode 6) variables command received response with other opcode than 2", state.address)
+            },
+            _ => {
+                vvprintln!("{} (mode 6) variables command received non-control packet", state.address)
             }
-        },
-        _ => {
-            vvprintln!("{} (mode 6) variables command received non-control packet", state.address)
+        }
+
+        ScanTypeStatus::Continue
+    }
+
+    fn timeout(&mut self, state: &mut ScanState) -> ScanTypeStatus {
+        if self.retries < state.maxretries {
+            self.queue_request(state);
+            self.retries += 1;
+            ScanTypeStatus::Continue
+        } else {
+            vprintln!("{} mode 6 timed out", state.address);
+            ScanTypeStatus::Done
         }
     }
 
-    ScanTypeStatus::Continue
+    fn contribute(&self, result: &mut ScanResult) {
+        result.variables = self.variables.as_ref().map(|v| v.str.clone());
+    }
 }
 
-pub fn timeout(state: &mut ScanState) -> ScanTypeStatus {
-    if state.version_request_status.retries < state.maxretries {
-        let mut msg = NtpControlMessage::empty();
-        msg.version = 3;
-        msg.opcode = 2;
-        state.queue.push_back(AnyNTPPacket::Control(msg));
-        state.version_request_status.retries += 1;
-        ScanTypeStatus::Continue
-    } else {
-        vprintln!("{} mode 6 timed out", state.address);
-        ScanTypeStatus::Done
+/// Split a mode 6 variable list (`name=value, name="quoted, value", ...`) into its pairs.
+/// Variables without a value get an empty string.
+pub fn parse_variables(str: &str) -> Vec<(String, String)> {
+    let mut vars = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in str.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            },
+            ',' if !quoted => {
+                vars.push(std::mem::take(&mut current));
+            },
+            _ => current.push(c),
+        }
     }
+    vars.push(current);
+
+    vars.iter()
+        .map(|v| v.trim_matches(|c: char| c.is_whitespace() || c == '\0'))
+        .filter(|v| !v.is_empty())
+        .map(|v| match v.split_once('=') {
+            Some((name, value)) => (name.trim().to_string(), value.trim().trim_matches('"').to_string()),
+            None => (v.to_string(), String::new()),
+        })
+        .collect()
+}
+
+#[test]
+fn variables_are_split() {
+    let vars = parse_variables("version=\"ntpd 4.2.8p15, built\", stratum=2,\r\nleap=00, flash\r\n");
+    assert_eq!(vars, vec![
+        ("version".to_string(), "ntpd 4.2.8p15, built".to_string()),
+        ("stratum".to_string(), "2".to_string()),
+        ("leap".to_string(), "00".to_string()),
+        ("flash".to_string(), "".to_string()),
+    ]);
 }